@@ -1,22 +1,72 @@
-use std::collections::HashMap;
+use std::cmp::Ordering as KeyOrdering;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use bytes::{BufMut, Bytes, BytesMut};
-use parking_lot::Mutex;
+use bytes::{Bytes, BytesMut};
+use parking_lot::{Mutex, RwLock};
 use prost::{decode_length_delimiter, encode_length_delimiter};
 
+use crate::blob::BlobStore;
 // use crate::data::log_record::LogRecordType::TXN_FINISHED;
-use crate::data::log_record::{LogRecord, LogRecordType};
+use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType};
 use crate::data::log_record::LogRecordType::TXN_FINISHED;
 use crate::db::Engine;
 use crate::errors::{Errors, Result};
-use crate::options::WriteBatchOptions;
+use crate::index::IterCursor;
+use crate::iterator::{KeyIterator, LazyValue};
+use crate::options::{IteratorOptions, WriteBatchOptions};
 
 const TXN_FIN_KEY: &[u8] = "txn-fin".as_bytes();
 //用来标识非事务(即非批量写入的key),批量写入的key其seq_no从1开始
 pub(crate) const NON_TRANSACTION_SEQ_NO: usize = 0;
 
+//保存点之后被修改的key和修改之前暂存的数据,None说明修改之前batch中没有这个key
+type UndoLog = Vec<(Vec<u8>, Option<LogRecord>)>;
+
+//batch中的数据写入数据文件的位置,大value还有其在blob文件中的位置
+#[derive(Clone, Copy)]
+struct WrittenPos {
+	pos: LogRecordPos,
+	blob_pos: Option<LogRecordPos>,
+	rec_type: LogRecordType,
+}
+
+//spill模式下已经写入数据文件但还没有提交的数据
+struct SpillState {
+	//写入时key使用的序列号,提交时用同一个序列号写入TXN_FINISHED
+	seq_no: usize,
+	//每个key最新一次写入的位置和类型,spill模式下pending_writes中不再暂存数据
+	positions: HashMap<Vec<u8>, WrittenPos>,
+	//和Engine共享,不持有Engine的引用,batch可以在Engine之后drop
+	reclaimable_sizes: Arc<RwLock<HashMap<u32, u64>>>,
+	blob_store: Arc<BlobStore>,
+	spilling_batches: Arc<AtomicUsize>,
+}
+
+impl Drop for SpillState {
+	//提交时positions已经被取走了,剩下的是没有提交的数据
+	//没有TXN_FINISHED标识,这些数据在加载时会被丢弃,其占用的空间可以回收
+	fn drop(&mut self) {
+		let mut reclaimable_sizes = self.reclaimable_sizes.write();
+		for written in self.positions.values() {
+			*reclaimable_sizes.entry(written.pos.file_id).or_insert(0) += written.pos.size as u64;
+			if let Some(blob_pos) = written.blob_pos {
+				self.blob_store.add_garbage(blob_pos);
+			}
+		}
+		self.spilling_batches.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+//batch中暂存的value,spill模式下只有数据在文件中的位置
+#[derive(Clone)]
+enum PendingValue {
+	InMemory(Bytes),
+	Spilled(LogRecordPos),
+}
+
 //批量写数据,保证原子性
 pub struct WriteBatch<'a> {
 	//使用hashmap对比数组的优点为可以去除重复的key
@@ -24,14 +74,20 @@ pub struct WriteBatch<'a> {
 	//暂存用户写入的数据
 	engine: &'a Engine,//engine生命周期>=write_batch
 	options: WriteBatchOptions,
+	//每个保存点一份撤销日志,回滚时按相反的顺序恢复暂存的数据
+	savepoints: Mutex<Vec<UndoLog>>,
+	//spill模式下第一次写入时创建
+	spill: Mutex<Option<SpillState>>,
 }
 
 impl Engine {
-	pub fn new_write_batch(&self, write_batch_options: WriteBatchOptions) -> WriteBatch {
+	pub fn new_write_batch(&self, write_batch_options: WriteBatchOptions) -> WriteBatch<'_> {
 		WriteBatch {
 			pending_writes: Arc::new(Mutex::new(HashMap::new())),
 			engine: self,
 			options: write_batch_options,
+			savepoints: Mutex::new(Vec::new()),
+			spill: Mutex::new(None),
 		}
 	}
 }
@@ -46,87 +102,345 @@ impl WriteBatch<'_> {
 			key: key.to_vec(),
 			value: value.to_vec(),
 			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
 		};
+		if self.options.spill_to_disk {
+			return self.spill(record);
+		}
 		//暂存数据
 		let mut pending_writes = self.pending_writes.lock();
-		pending_writes.insert(key.to_vec(), record);
+		let old_record = pending_writes.insert(key.to_vec(), record);
+		self.record_undo(&key, old_record);
 		Ok(())
 	}
 	pub fn delete(&self, key: Bytes) -> Result<()> {
+		self.delete_with(key, false)
+	}
+	//事务提交之前其他写入可能创建了这个key,不能根据当前的索引跳过,总是写墓碑值
+	pub(crate) fn delete_always(&self, key: Bytes) -> Result<()> {
+		self.delete_with(key, true)
+	}
+	fn delete_with(&self, key: Bytes, always: bool) -> Result<()> {
 		if key.is_empty() {
 			return Err(Errors::KeyIsEmpty);
 		}
+		let record = LogRecord {
+			key: key.to_vec(),
+			value: vec![],
+			rec_type: LogRecordType::DELETED,
+			expire_at: 0,
+		};
+		//spill模式下之前的写入已经在数据文件里面了,只能写墓碑值覆盖
+		if self.options.spill_to_disk {
+			return self.spill(record);
+		}
 		let mut pending_writes = self.pending_writes.lock();
-		if self.engine.indexer.get(key.to_vec()).is_none() {
+		if !always && self.engine.indexer.get(key.to_vec()).is_none() {
 			//虽然key可能在数据库中不存在,但是可能存在于batch中,直接在暂存的数据里面删除即可
-			if pending_writes.contains_key(&key.to_vec()) {
-				pending_writes.remove(&key.to_vec());
+			if let Some(old_record) = pending_writes.remove(&key.to_vec()) {
+				self.record_undo(&key, Some(old_record));
 			}
 			return Ok(());
 		}
-		let record = LogRecord {
-			key: key.to_vec(),
-			value: vec![],
-			rec_type: LogRecordType::DELETED,
+		let old_record = pending_writes.insert(key.to_vec(), record);
+		self.record_undo(&key, old_record);
+		Ok(())
+	}
+	//设置一个保存点,之后的修改可以通过rollback_to_savepoint撤销,保存点可以嵌套
+	pub fn set_savepoint(&self) {
+		self.savepoints.lock().push(Vec::new());
+	}
+	//把暂存的数据恢复到最近一次set_savepoint时的状态,并移除这个保存点
+	//spill模式下写入的数据已经在数据文件里面了,不支持回滚
+	pub fn rollback_to_savepoint(&self) -> Result<()> {
+		if self.options.spill_to_disk {
+			return Err(Errors::SpilledBatchNotRevertible);
+		}
+		let mut pending_writes = self.pending_writes.lock();
+		let undo_log = self.savepoints.lock().pop().ok_or(Errors::NoSavepoint)?;
+		for (key, old_record) in undo_log.into_iter().rev() {
+			match old_record {
+				Some(record) => pending_writes.insert(key, record),
+				None => pending_writes.remove(&key),
+			};
+		}
+		Ok(())
+	}
+	//清空暂存的数据和所有保存点
+	pub fn clear(&self) {
+		let mut pending_writes = self.pending_writes.lock();
+		pending_writes.clear();
+		self.savepoints.lock().clear();
+		//已经写入的数据不会再被提交,之后的写入使用新的序列号
+		self.spill.lock().take();
+	}
+	//spill模式下把数据直接追加到数据文件中,key带上batch的序列号,内存中只保留key的位置
+	fn spill(&self, record: LogRecord) -> Result<()> {
+		let mut spill = self.spill.lock();
+		let state = spill.get_or_insert_with(|| {
+			//merge会等到所有spill的batch提交或者放弃之后再进行,防止未提交的数据被merge丢掉
+			let spilling_batches = self.engine.merge_context.spilling_batches.clone();
+			spilling_batches.fetch_add(1, Ordering::SeqCst);
+			SpillState {
+				seq_no: self.engine.seq_no.fetch_add(1, Ordering::SeqCst) + 1,
+				positions: HashMap::new(),
+				reclaimable_sizes: self.engine.reclaimable_sizes.clone(),
+				blob_store: self.engine.blob_store.clone(),
+				spilling_batches,
+			}
+		});
+		let rec_type = record.rec_type;
+		let mut spilled_record = LogRecord {
+			key: log_record_key_with_seq(record.key.clone(), state.seq_no),
+			value: record.value,
+			rec_type,
+			expire_at: record.expire_at,
 		};
-		pending_writes.insert(key.to_vec(), record);
+		let blob_pos = self.engine.separate_blob_value(&record.key, &mut spilled_record)?;
+		let pos = self.engine.append_log_record(&mut spilled_record)?;
+		//同一个batch中被覆盖的数据无论是否提交都是无效的
+		let written = WrittenPos { pos, blob_pos, rec_type };
+		if let Some(old) = state.positions.insert(record.key, written) {
+			self.engine.add_reclaimable(old.pos);
+			if let Some(blob_pos) = old.blob_pos {
+				self.engine.blob_store.add_garbage(blob_pos);
+			}
+		}
 		Ok(())
 	}
+	//调用方需要持有pending_writes的锁,没有保存点时不需要记录
+	fn record_undo(&self, key: &[u8], old_record: Option<LogRecord>) {
+		if let Some(undo_log) = self.savepoints.lock().last_mut() {
+			undo_log.push((key.to_vec(), old_record));
+		}
+	}
+	//先读batch中暂存的数据,batch中没有再读数据库
+	pub fn get(&self, key: Bytes) -> Result<Bytes> {
+		if key.is_empty() {
+			return Err(Errors::KeyIsEmpty);
+		}
+		match self.get_pending(&key)? {
+			Some(value) => value.ok_or(Errors::KeyNotFound),
+			None => self.engine.get(key),
+		}
+	}
+	//获取batch和数据库合并之后的迭代器,batch中的数据会覆盖数据库中的数据
+	//创建时会拷贝一份暂存的数据,之后对batch的修改对迭代器不可见
+	pub fn iter(&self, iterator_options: &IteratorOptions) -> BatchIterator<'_> {
+		let pending = match self.options.spill_to_disk {
+			true => self.spill.lock().as_ref().map_or_else(BTreeMap::new, |state| {
+				state.positions.iter().map(|(key, written)| (key.clone(), spilled_value(written))).collect()
+			}),
+			false => {
+				let pending_writes = self.pending_writes.lock();
+				pending_writes.iter().map(|(key, record)| (key.clone(), pending_value(record))).collect()
+			}
+		};
+		BatchIterator {
+			engine: self.engine,
+			pending,
+			pending_cursor: IterCursor::new(iterator_options),
+			pending_head: None,
+			key_iter: self.engine.key_iter(iterator_options),
+			engine_head: None,
+			reverse: iterator_options.reverse,
+		}
+	}
+	//暂存数据中key对应的value,外层返回None说明batch中没有这个key,内层返回None说明key在batch中被删除了
+	pub(crate) fn get_pending(&self, key: &[u8]) -> Result<Option<Option<Bytes>>> {
+		let value = match self.options.spill_to_disk {
+			true => self.spill.lock().as_ref().and_then(|state| state.positions.get(key)).map(spilled_value),
+			false => self.pending_writes.lock().get(key).map(pending_value),
+		};
+		let Some(value) = value else {
+			return Ok(None);
+		};
+		match value {
+			Some(PendingValue::InMemory(value)) => Ok(Some(Some(value))),
+			Some(PendingValue::Spilled(pos)) => Ok(Some(Some(self.engine.get_value_by_position(pos)?))),
+			None => Ok(Some(None)),
+		}
+	}
 	//提交数据,将数据写到文件中,并更新内存索引
 	pub fn commit(&self) -> Result<()> {
+		//获取全局锁,加锁保证串行化
+		let _lock = self.engine.batch_commit_lock.write();
+		self.commit_with_lock()
+	}
+	//调用方需要持有batch_commit_lock的写锁,事务在同一把锁里先做冲突检测再提交
+	pub(crate) fn commit_with_lock(&self) -> Result<()> {
 		let mut pending_writes = self.pending_writes.lock();
-		if pending_writes.len() == 0 {
+		let mut spill = self.spill.lock();
+		if pending_writes.is_empty() && spill.is_none() {
 			return Ok(());
 		}
-		//一次写入的批次不能太大,防止内存用掉太多
-		if pending_writes.len() > self.options.max_batch_num {
-			return Err(Errors::ExceedMaxBatchNum);
-		}
-		//获取全局锁,加锁保证串行化
-		let _lock = self.engine.batch_commit_lock.lock();
-		//获取全局的事务序列号
-		//这个方法给原子类型+1并返回旧的值
-		let seq_no = self.engine.seq_no.fetch_add(1, Ordering::SeqCst) + 1; //得到序列号后在递增
-
-		//最后要统一更新的内存索引,先暂存在一个哈希表里面 
+		//最后要统一更新的内存索引,先暂存在一个哈希表里面
 		let mut positions = HashMap::new();
-		//写数据到数据文件中
-		for (_, item) in pending_writes.iter() {
-			let mut record = LogRecord {
-				key: log_record_key_with_seq(item.key.clone(), seq_no),
-				value: item.key.clone(),
-				rec_type: item.rec_type,
-			};
-			let pos = self.engine.append_log_record(&mut record)?;
-			positions.insert(item.key.clone(), pos);
-		}
+		let seq_no = match spill.as_ref() {
+			//数据在写入时已经追加到数据文件中了,只需要再写TXN_FINISHED
+			Some(state) => state.seq_no,
+			None => {
+				//一次写入的批次不能太大,防止内存用掉太多
+				if pending_writes.len() > self.options.max_batch_num {
+					return Err(Errors::ExceedMaxBatchNum);
+				}
+				//获取全局的事务序列号
+				//这个方法给原子类型+1并返回旧的值
+				let seq_no = self.engine.seq_no.fetch_add(1, Ordering::SeqCst) + 1; //得到序列号后在递增
+
+				//写数据到数据文件中
+				for (_, item) in pending_writes.iter() {
+					let mut record = LogRecord {
+						key: log_record_key_with_seq(item.key.clone(), seq_no),
+						value: item.value.clone(),
+						rec_type: item.rec_type,
+						expire_at: item.expire_at,
+					};
+					let blob_pos = self.engine.separate_blob_value(&item.key, &mut record)?;
+					let pos = self.engine.append_log_record(&mut record)?;
+					let written = WrittenPos { pos, blob_pos, rec_type: item.rec_type };
+					positions.insert(item.key.clone(), written);
+				}
+				seq_no
+			}
+		};
 		//写最后一条标识事务完成的数据
 		let mut finish_record = LogRecord {
 			key: log_record_key_with_seq(TXN_FIN_KEY.to_vec(), seq_no),
 			value: vec![],
 			rec_type: TXN_FINISHED,
+			expire_at: 0,
 		};
-		self.engine.append_log_record(&mut finish_record)?;
+		let finish_pos = self.engine.append_log_record(&mut finish_record)?;
+		self.engine.add_reclaimable(finish_pos);
 		//将数据持久化
 		if self.options.sync_writes {
 			self.engine.sync()?;
 		}
+		//spill模式的序列号在第一次写入时就分配了,快照按提交的顺序判断是否可见,这里重新分配一个
+		//提交成功之后spill的数据不再需要回收
+		let seq_no = match spill.take() {
+			Some(mut state) => {
+				positions = std::mem::take(&mut state.positions);
+				self.engine.seq_no.fetch_add(1, Ordering::SeqCst) + 1
+			}
+			None => seq_no,
+		};
 		//执行到这里说明前面的数据都已经写入到了DataFile里面
 		//数据全部写完之后再更新内存索引
-		for (_, item) in pending_writes.iter() {
-			let record_pos = positions.get(&item.key).unwrap();
-			if item.rec_type == LogRecordType::NORMAL {
-				self.engine.indexer.put(item.key.clone(), *record_pos);
-			} else if item.rec_type == LogRecordType::DELETED {
-				self.engine.indexer.delete(item.key.clone());
+		for (key, written) in positions {
+			if written.rec_type == LogRecordType::NORMAL {
+				if let Some(blob_pos) = written.blob_pos {
+					self.engine.blob_store.set_pointer(written.pos, blob_pos);
+				}
+				self.engine.commit_index(key, Some(written.pos), seq_no);
+			} else {
+				//墓碑值本身也是可以回收的
+				self.engine.add_reclaimable(written.pos);
+				self.engine.commit_index(key, None, seq_no);
 			}
 		}
 		//清空暂存数据,防止其影响下一次的批量提交
 		pending_writes.clear();
+		self.savepoints.lock().clear();
 		Ok(())
 	}
 }
 
+//暂存的record对应的value,返回None说明key在batch中被删除了
+fn pending_value(record: &LogRecord) -> Option<PendingValue> {
+	(record.rec_type == LogRecordType::NORMAL).then(|| PendingValue::InMemory(Bytes::from(record.value.clone())))
+}
+
+//spill的数据对应的value,返回None说明key在batch中被删除了
+fn spilled_value(written: &WrittenPos) -> Option<PendingValue> {
+	(written.rec_type == LogRecordType::NORMAL).then_some(PendingValue::Spilled(written.pos))
+}
+
+//batch和数据库合并之后的迭代器
+pub struct BatchIterator<'a> {
+	engine: &'a Engine,
+	pending: BTreeMap<Vec<u8>, Option<PendingValue>>,
+	pending_cursor: IterCursor<Option<PendingValue>>,
+	//两边各自预读的下一条数据
+	pending_head: Option<(Vec<u8>, Option<PendingValue>)>,
+	key_iter: KeyIterator<'a>,
+	engine_head: Option<(Bytes, LazyValue<'a>)>,
+	reverse: bool,
+}
+
+impl BatchIterator<'_> {
+	pub fn rewind(&mut self) {
+		self.pending_cursor.rewind();
+		self.key_iter.rewind();
+		self.pending_head = None;
+		self.engine_head = None;
+	}
+	pub fn seek(&mut self, key: Vec<u8>) {
+		self.pending_cursor.seek(key.clone());
+		self.key_iter.seek(key);
+		self.pending_head = None;
+		self.engine_head = None;
+	}
+}
+
+impl std::iter::Iterator for BatchIterator<'_> {
+	type Item = Result<(Bytes, Bytes)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if self.pending_head.is_none() {
+				let pending = &self.pending;
+				self.pending_head = self
+					.pending_cursor
+					.next(|bound, ascending| {
+						let item = match ascending {
+							true => pending.range::<[u8], _>((bound, Bound::Unbounded)).next(),
+							false => pending.range::<[u8], _>((Bound::Unbounded, bound)).next_back(),
+						};
+						item.map(|(key, value)| (key.clone(), value.clone()))
+					})
+					.map(|(key, value)| (key.clone(), value.clone()));
+			}
+			if self.engine_head.is_none() {
+				self.engine_head = self.key_iter.next();
+			}
+			let order = match (&self.pending_head, &self.engine_head) {
+				(None, None) => return None,
+				(Some(_), None) => KeyOrdering::Less,
+				(None, Some(_)) => KeyOrdering::Greater,
+				(Some((pending_key, _)), Some((engine_key, _))) => {
+					let order = pending_key.as_slice().cmp(engine_key.as_ref());
+					match self.reverse {
+						false => order,
+						true => order.reverse(),
+					}
+				}
+			};
+			if order == KeyOrdering::Greater {
+				let (key, value) = self.engine_head.take().unwrap();
+				match value.value() {
+					//KeyIterator检查过期之后读取value之前key可能刚好过期,这时跳过这个key
+					Err(Errors::KeyNotFound) => continue,
+					value => return Some(value.map(|value| (key, value))),
+				}
+			}
+			//同一个key以batch中的数据为准
+			if order == KeyOrdering::Equal {
+				self.engine_head = None;
+			}
+			let (key, value) = self.pending_head.take().unwrap();
+			//batch中被删除的key直接跳过
+			let value = match value {
+				Some(PendingValue::InMemory(value)) => Ok(value),
+				Some(PendingValue::Spilled(pos)) => self.engine.get_value_by_position(pos),
+				None => continue,
+			};
+			return Some(value.map(|value| (Bytes::from(key), value)));
+		}
+	}
+}
+
 //编码seq_no和key
 pub(crate) fn log_record_key_with_seq(key: Vec<u8>, seq_no: usize) -> Vec<u8> {
 	let mut enc_key = BytesMut::new();
@@ -136,17 +450,17 @@ pub(crate) fn log_record_key_with_seq(key: Vec<u8>, seq_no: usize) -> Vec<u8> {
 }
 
 //由于把seq_no和key编码到一起了,那么更新索引的时候需要把seq_no和key分开
-pub(crate) fn parse_log_record_key(key: &[u8]) -> (Vec<u8>, usize) {
-	let mut buf = BytesMut::new();
-	buf.put_slice(key);
+pub(crate) fn parse_log_record_key(key: &[u8]) -> Result<(Vec<u8>, usize)> {
+	let mut buf = key;
 	//这里传入&mut buf的原因是因为这个方法会修改里面的cursor
-	let seq_no = decode_length_delimiter(&mut buf).unwrap();
-	(buf.to_vec(), seq_no)
+	let seq_no = decode_length_delimiter(&mut buf).map_err(|_| Errors::InvalidLogRecordKey)?;
+	Ok((buf.to_vec(), seq_no))
 }
 
 #[cfg(test)]
 mod test {
 	use std::path::PathBuf;
+	use std::time::Duration;
 
 	use crate::options::Options;
 	use crate::util;
@@ -154,6 +468,7 @@ mod test {
 	use super::*;
 
 	#[test]
+	#[allow(clippy::field_reassign_with_default)]
 	fn test_write_batch_1() {
 		let mut opts = Options::default();
 		opts.dir_path = PathBuf::from("/tmp/bitcask-rs-batch-1");
@@ -193,6 +508,7 @@ mod test {
 	}
 
 	#[test]
+	#[allow(clippy::field_reassign_with_default)]
 	fn test_write_batch_2() {
 		let mut opts = Options::default();
 		opts.dir_path = PathBuf::from("/tmp/bitcask-rs-batch-2");
@@ -239,4 +555,210 @@ mod test {
 		// 删除测试的文件夹
 		std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_parse_log_record_key() {
+		let enc_key = log_record_key_with_seq("name".as_bytes().to_vec(), 300);
+		let (key, seq_no) = parse_log_record_key(&enc_key).unwrap();
+		assert_eq!("name".as_bytes().to_vec(), key);
+		assert_eq!(300, seq_no);
+
+		//seq_no损坏时返回错误
+		assert_eq!(Errors::InvalidLogRecordKey, parse_log_record_key(&[]).err().unwrap());
+		assert_eq!(Errors::InvalidLogRecordKey, parse_log_record_key(&[0xff; 4]).err().unwrap());
+	}
+
+	#[test]
+	fn test_write_batch_get_and_iter() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-batch-iter"),
+			data_file_size: 64 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for key in ["a", "b", "c", "d"] {
+			engine.put(Bytes::from(key), Bytes::from(key.repeat(2))).unwrap();
+		}
+
+		let wb = engine.new_write_batch(WriteBatchOptions::default());
+		wb.put(Bytes::from("b"), Bytes::from("new")).unwrap();
+		wb.delete(Bytes::from("c")).unwrap();
+		wb.put(Bytes::from("bb"), Bytes::from("new")).unwrap();
+		wb.put(Bytes::from("e"), Bytes::from("new")).unwrap();
+		//batch中没有的key再读数据库
+		assert_eq!(Bytes::from("aa"), wb.get(Bytes::from("a")).unwrap());
+		assert_eq!(Bytes::from("new"), wb.get(Bytes::from("b")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), wb.get(Bytes::from("c")));
+		assert_eq!(Err(Errors::KeyNotFound), wb.get(Bytes::from("f")));
+		assert_eq!(Err(Errors::KeyIsEmpty), wb.get(Bytes::new()));
+
+		let items = |iter: BatchIterator| -> Vec<(Bytes, Bytes)> {
+			iter.map(|item| item.expect("failed to read value")).collect()
+		};
+		let expected: Vec<(Bytes, Bytes)> = [("a", "aa"), ("b", "new"), ("bb", "new"), ("d", "dd"), ("e", "new")]
+			.iter()
+			.map(|(key, value)| (Bytes::from(*key), Bytes::from(*value)))
+			.collect();
+		assert_eq!(expected, items(wb.iter(&IteratorOptions::default())));
+
+		let reverse_opts = IteratorOptions {
+			reverse: true,
+			..Default::default()
+		};
+		let reversed: Vec<(Bytes, Bytes)> = expected.iter().rev().cloned().collect();
+		assert_eq!(reversed, items(wb.iter(&reverse_opts)));
+		let mut iter = wb.iter(&reverse_opts);
+		iter.seek("c".as_bytes().to_vec());
+		assert_eq!(Bytes::from("bb"), iter.next().unwrap().unwrap().0);
+		iter.rewind();
+		assert_eq!(Bytes::from("e"), iter.next().unwrap().unwrap().0);
+
+		let iter = wb.iter(&IteratorOptions {
+			prefix: "b".as_bytes().to_vec(),
+			..Default::default()
+		});
+		assert_eq!(expected[1..3].to_vec(), items(iter));
+
+		//提交之前数据库中的数据不变
+		assert_eq!(Bytes::from("cc"), engine.get(Bytes::from("c")).unwrap());
+		assert_eq!(4, engine.list_keys().len());
+
+		std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_write_batch_iter_with_expired_keys() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-batch-iter-ttl"),
+			data_file_size: 64 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let short = Duration::from_millis(100);
+		engine.put_with_ttl(Bytes::from("a"), Bytes::from("aa"), short).unwrap();
+		engine.put(Bytes::from("b"), Bytes::from("bb")).unwrap();
+		engine.put_with_ttl(Bytes::from("c"), Bytes::from("cc"), short).unwrap();
+		let wb = engine.new_write_batch(WriteBatchOptions::default());
+		wb.put(Bytes::from("d"), Bytes::from("new")).unwrap();
+		std::thread::sleep(short * 2);
+		//去掉c的过期时间,模拟检查过期之后才过期的key
+		engine.expirations.remove(engine.indexer.get(b"c".to_vec()).unwrap());
+
+		let keys: Vec<Bytes> = wb
+			.iter(&IteratorOptions::default())
+			.map(|item| item.expect("failed to read value").0)
+			.collect();
+		assert_eq!(vec![Bytes::from("b"), Bytes::from("d")], keys);
+
+		std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_write_batch_savepoint() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-batch-savepoint"),
+			data_file_size: 64 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		engine.put(Bytes::from("a"), Bytes::from("a0")).unwrap();
+
+		let wb = engine.new_write_batch(WriteBatchOptions::default());
+		assert_eq!(Err(Errors::NoSavepoint), wb.rollback_to_savepoint());
+		wb.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+		wb.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+
+		wb.set_savepoint();
+		wb.put(Bytes::from("a"), Bytes::from("a2")).unwrap();
+		//数据库中不存在的key,删除时直接从暂存的数据中移除
+		wb.delete(Bytes::from("b")).unwrap();
+		wb.put(Bytes::from("c"), Bytes::from("c1")).unwrap();
+		//嵌套的保存点
+		wb.set_savepoint();
+		wb.delete(Bytes::from("a")).unwrap();
+		wb.put(Bytes::from("b"), Bytes::from("b2")).unwrap();
+		assert_eq!(Err(Errors::KeyNotFound), wb.get(Bytes::from("a")));
+
+		assert!(wb.rollback_to_savepoint().is_ok());
+		assert_eq!(Bytes::from("a2"), wb.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), wb.get(Bytes::from("b")));
+		assert_eq!(Bytes::from("c1"), wb.get(Bytes::from("c")).unwrap());
+
+		assert!(wb.rollback_to_savepoint().is_ok());
+		assert_eq!(Bytes::from("a1"), wb.get(Bytes::from("a")).unwrap());
+		assert_eq!(Bytes::from("b1"), wb.get(Bytes::from("b")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), wb.get(Bytes::from("c")));
+		assert_eq!(2, wb.pending_writes.lock().len());
+		assert_eq!(Err(Errors::NoSavepoint), wb.rollback_to_savepoint());
+
+		//清空之后读到的是数据库中的数据
+		wb.set_savepoint();
+		wb.clear();
+		assert_eq!(Bytes::from("a0"), wb.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::NoSavepoint), wb.rollback_to_savepoint());
+		wb.put(Bytes::from("d"), Bytes::from("d1")).unwrap();
+		assert!(wb.commit().is_ok());
+		assert_eq!(Bytes::from("a0"), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(Bytes::from("d1"), engine.get(Bytes::from("d")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("b")));
+
+		std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_write_batch_spill_to_disk() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-batch-spill"),
+			data_file_size: 64 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		engine.put(util::rand_kv::get_test_key(0), Bytes::from("old value")).unwrap();
+		let spill_opts = || WriteBatchOptions {
+			max_batch_num: 10,
+			spill_to_disk: true,
+			..Default::default()
+		};
+
+		//数据量超过了max_batch_num,写入时就已经追加到数据文件中
+		let wb = engine.new_write_batch(spill_opts());
+		for i in 0..2000 {
+			wb.put(util::rand_kv::get_test_key(i), util::rand_kv::get_test_value(i)).unwrap();
+		}
+		wb.delete(util::rand_kv::get_test_key(1)).unwrap();
+		assert!(engine.stat().unwrap().data_file_num > 1);
+		//内存中只保留写入的位置,不再暂存数据
+		assert!(wb.pending_writes.lock().is_empty());
+		assert_eq!(2000, wb.spill.lock().as_ref().unwrap().positions.len());
+		assert_eq!(util::rand_kv::get_test_value(0), wb.get(util::rand_kv::get_test_key(0)).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), wb.get(util::rand_kv::get_test_key(1)));
+		assert_eq!(1999, wb.iter(&IteratorOptions::default()).count());
+		assert_eq!(Err(Errors::SpilledBatchNotRevertible), wb.rollback_to_savepoint());
+		//提交之前对数据库不可见,也不能进行merge
+		assert_eq!(Bytes::from("old value"), engine.get(util::rand_kv::get_test_key(0)).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(util::rand_kv::get_test_key(2)));
+		assert_eq!(Err(Errors::SpilledBatchInProgress), engine.merge());
+		assert!(wb.commit().is_ok());
+		assert_eq!(util::rand_kv::get_test_value(0), engine.get(util::rand_kv::get_test_key(0)).unwrap());
+		assert_eq!(1999, engine.list_keys().len());
+
+		//没有提交的batch在重启之后被丢弃
+		let wb = engine.new_write_batch(spill_opts());
+		wb.put(util::rand_kv::get_test_key(5000), util::rand_kv::get_test_value(5000)).unwrap();
+		wb.delete(util::rand_kv::get_test_key(2)).unwrap();
+		drop(wb);
+		assert!(engine.merge().is_ok());
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(1999, engine.list_keys().len());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(util::rand_kv::get_test_key(1)));
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(util::rand_kv::get_test_key(5000)));
+		for i in [0, 2, 1999] {
+			assert_eq!(util::rand_kv::get_test_value(i), engine.get(util::rand_kv::get_test_key(i)).unwrap());
+		}
+
+		std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+	}
+}
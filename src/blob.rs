@@ -0,0 +1,568 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use log::{error, info};
+use parking_lot::{Mutex, RwLock};
+
+use crate::data::data_file::{BLOB_FILE_NAME_SUFFIX, DataFile, get_blob_file_name};
+use crate::data::log_record::{decode_log_record_pos, LogRecord, LogRecordPos, LogRecordType, ReadLogRecord};
+use crate::db::{Engine, is_corrupted_record_err, read_log_record_at};
+use crate::encryption::KeyProvider;
+use crate::errors::{Errors, Result};
+use crate::options::{Compression, Options};
+use crate::ttl::now_millis;
+
+//大value单独存放在blob文件中,数据文件里的记录只保存其在blob文件中的位置,merge时不需要重写value
+//blob文件中的失效数据由Engine::gc_blobs回收,和数据文件的merge互相独立
+pub(crate) struct BlobStore {
+	dir_path: PathBuf,
+	file_size: u64,
+	sync_writes: bool,
+	compression: Compression,
+	key_provider: Option<Arc<dyn KeyProvider>>,
+	//当前写入的blob文件,第一次写入大value时才创建
+	active_file: RwLock<Option<DataFile>>,
+	older_files: RwLock<HashMap<u32, DataFile>>,
+	next_file_id: AtomicU32,
+	//数据文件中blob记录的(file_id, offset) -> value在blob文件中的位置
+	//只保存内存索引和操作数链表能读到的记录,被覆盖之后其value计入失效数据
+	pointers: RwLock<HashMap<(u32, u64), LogRecordPos>>,
+	//每个blob文件中失效的字节数
+	garbage_sizes: RwLock<HashMap<u32, u64>>,
+	//有效数据已经全部重写过的文件,等到下一次gc时再删除,之前读到旧位置的请求仍然可以读取
+	//文件删除之后再用旧位置读取时,Engine::get_value_by_position会重新查找索引
+	obsolete_files: Mutex<HashSet<u32>>,
+	//保证同一时刻只有一个gc在进行
+	gc_lock: Mutex<()>,
+}
+
+impl BlobStore {
+	pub(crate) fn open(opts: &Options) -> Result<BlobStore> {
+		let mut file_ids = load_blob_file_ids(&opts.dir_path)?;
+		file_ids.sort_unstable();
+		let mut older_files = HashMap::new();
+		for file_id in file_ids.iter() {
+			let file = DataFile::new_blob_file(&opts.dir_path, *file_id)?.with_key_provider(opts.key_provider.clone());
+			older_files.insert(*file_id, file);
+		}
+		//id最大的文件继续写入,末尾没有写完的value没有被数据文件引用,会在gc时回收
+		let active_file = file_ids.last().map(|file_id| {
+			let file = older_files.remove(file_id).unwrap();
+			file.set_write_off(file.file_size());
+			file
+		});
+		Ok(BlobStore {
+			dir_path: opts.dir_path.clone(),
+			file_size: opts.data_file_size,
+			sync_writes: opts.sync_writes,
+			compression: opts.compression,
+			key_provider: opts.key_provider.clone(),
+			active_file: RwLock::new(active_file),
+			older_files: RwLock::new(older_files),
+			next_file_id: AtomicU32::new(file_ids.last().map_or(0, |file_id| file_id + 1)),
+			pointers: RwLock::new(HashMap::new()),
+			garbage_sizes: RwLock::new(HashMap::new()),
+			obsolete_files: Mutex::new(HashSet::new()),
+			gc_lock: Mutex::new(()),
+		})
+	}
+
+	//写入一个value,返回其在blob文件中的位置
+	//调用方需要持有batch_commit_lock,或者在spill的batch中写入,保证gc时不会丢掉这个value
+	pub(crate) fn write(&self, key: &[u8], value: &[u8]) -> Result<LogRecordPos> {
+		//blob文件中的key不带序列号,gc时用来查找内存索引
+		let record = LogRecord {
+			key: key.to_vec(),
+			value: value.to_vec(),
+			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
+		};
+		let enc_record = record.encode_with(self.compression, self.key_provider.as_deref())?;
+		let record_len = enc_record.len() as u64;
+		let mut active_file = self.active_file.write();
+		//活跃文件写满了,变为旧文件,一个value比文件还大时单独占一个文件
+		if active_file
+			.as_ref()
+			.is_some_and(|file| file.get_write_off() > 0 && file.get_write_off() + record_len > self.file_size)
+		{
+			self.seal(&mut active_file)?;
+		}
+		if active_file.is_none() {
+			let file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+			let file = DataFile::new_blob_file(&self.dir_path, file_id)?.with_key_provider(self.key_provider.clone());
+			*active_file = Some(file);
+		}
+		let file = active_file.as_ref().unwrap();
+		let write_off = file.get_write_off();
+		file.write(&enc_record)?;
+		//value要先于数据文件中的记录持久化
+		if self.sync_writes {
+			file.sync()?;
+		}
+		Ok(LogRecordPos {
+			file_id: file.get_file_id(),
+			offset: write_off,
+			size: record_len as u32,
+		})
+	}
+
+	//把活跃文件变为旧文件,下一次写入时再创建新的活跃文件
+	fn seal(&self, active_file: &mut Option<DataFile>) -> Result<()> {
+		if let Some(file) = active_file.take() {
+			file.sync()?;
+			self.older_files.write().insert(file.get_file_id(), file);
+		}
+		Ok(())
+	}
+
+	pub(crate) fn read(&self, pos: LogRecordPos) -> Result<Vec<u8>> {
+		Ok(self.read_record(pos.file_id, pos.offset)?.record.value)
+	}
+
+	fn read_record(&self, file_id: u32, offset: u64) -> Result<ReadLogRecord> {
+		let active_file = self.active_file.read();
+		if let Some(file) = active_file.as_ref().filter(|file| file.get_file_id() == file_id) {
+			return file.read_log_record(offset);
+		}
+		match self.older_files.read().get(&file_id) {
+			Some(file) => file.read_log_record(offset),
+			None => Err(Errors::DataFileNotFound),
+		}
+	}
+
+	//把数据文件中的blob记录替换为value在blob文件中的数据,在now时已经过期的记录不再读取value
+	pub(crate) fn materialize(&self, mut record: LogRecord, now: u64) -> Result<LogRecord> {
+		if record.rec_type == LogRecordType::BLOB_POINTER && !record.is_expired(now) {
+			record.value = self.read(decode_log_record_pos(record.value)?)?;
+			record.rec_type = LogRecordType::NORMAL;
+		}
+		Ok(record)
+	}
+
+	pub(crate) fn set_pointer(&self, pos: LogRecordPos, blob_pos: LogRecordPos) {
+		self.pointers.write().insert((pos.file_id, pos.offset), blob_pos);
+	}
+
+	pub(crate) fn pointer(&self, pos: LogRecordPos) -> Option<LogRecordPos> {
+		self.pointers.read().get(&(pos.file_id, pos.offset)).copied()
+	}
+
+	pub(crate) fn is_empty(&self) -> bool {
+		self.pointers.read().is_empty()
+	}
+
+	//pos处的记录不会再被读到,其value成为失效数据
+	pub(crate) fn release(&self, pos: LogRecordPos) {
+		let blob_pos = match self.pointers.write().remove(&(pos.file_id, pos.offset)) {
+			Some(blob_pos) => blob_pos,
+			None => return,
+		};
+		self.add_garbage(blob_pos);
+	}
+
+	//blob文件中blob_pos处的value不会再被读到
+	pub(crate) fn add_garbage(&self, blob_pos: LogRecordPos) {
+		*self.garbage_sizes.write().entry(blob_pos.file_id).or_insert(0) += blob_pos.size as u64;
+	}
+
+	//加载索引之后重新统计失效数据,文件中没有被引用的部分都是失效数据
+	pub(crate) fn reset_garbage_sizes(&self) {
+		let mut live_sizes: HashMap<u32, u64> = HashMap::new();
+		for blob_pos in self.pointers.read().values() {
+			*live_sizes.entry(blob_pos.file_id).or_insert(0) += blob_pos.size as u64;
+		}
+		let mut garbage_sizes = self.garbage_sizes.write();
+		garbage_sizes.clear();
+		for (file_id, file_size) in self.file_sizes() {
+			let live_size = live_sizes.get(&file_id).copied().unwrap_or(0);
+			garbage_sizes.insert(file_id, file_size.saturating_sub(live_size));
+		}
+	}
+
+	fn file_sizes(&self) -> Vec<(u32, u64)> {
+		let active_file = self.active_file.read();
+		let older_files = self.older_files.read();
+		let mut file_sizes: Vec<(u32, u64)> =
+			older_files.iter().map(|(file_id, file)| (*file_id, file.file_size())).collect();
+		if let Some(file) = active_file.as_ref() {
+			file_sizes.push((file.get_file_id(), file.file_size()));
+		}
+		file_sizes
+	}
+
+	//失效数据的比例达到阈值的文件,活跃文件达到阈值时先变为旧文件
+	//spill的batch写入的value在提交之前不在内存索引中,重写时会被丢掉,这时不能gc
+	fn gc_candidates(&self, ratio: f32, spilling_batches: &AtomicUsize) -> Result<Vec<u32>> {
+		let obsolete_files = self.obsolete_files.lock();
+		let garbage_sizes = self.garbage_sizes.read().clone();
+		let mut candidates: Vec<u32> = self
+			.file_sizes()
+			.into_iter()
+			.filter(|(file_id, file_size)| {
+				let garbage_size = garbage_sizes.get(file_id).copied().unwrap_or(0);
+				*file_size > 0 && !obsolete_files.contains(file_id) && garbage_size as f32 / *file_size as f32 >= ratio
+			})
+			.map(|(file_id, _)| file_id)
+			.collect();
+		candidates.sort_unstable();
+		let mut active_file = self.active_file.write();
+		if active_file.as_ref().is_some_and(|file| candidates.contains(&file.get_file_id())) {
+			self.seal(&mut active_file)?;
+		}
+		//拿到活跃文件的锁之后再检查,之后开始spill的batch只会写入新的活跃文件
+		if spilling_batches.load(Ordering::SeqCst) > 0 {
+			return Err(Errors::SpilledBatchInProgress);
+		}
+		Ok(candidates)
+	}
+
+	//删除上一次gc留下的文件,删除失败的文件重新打开之后全部是失效数据,会再次被gc
+	fn remove_obsolete_files(&self) {
+		let mut obsolete_files = self.obsolete_files.lock();
+		for file_id in obsolete_files.drain() {
+			drop(self.older_files.write().remove(&file_id));
+			self.garbage_sizes.write().remove(&file_id);
+			if let Err(e) = fs::remove_file(get_blob_file_name(&self.dir_path, file_id)) {
+				error!("failed to remove blob file {}: {}", file_id, e);
+			}
+		}
+	}
+
+	fn next_valid_record_offset(&self, file_id: u32, offset: u64) -> Option<u64> {
+		self.older_files.read().get(&file_id)?.next_valid_record_offset(offset)
+	}
+
+	pub(crate) fn sync(&self) -> Result<()> {
+		match self.active_file.read().as_ref() {
+			Some(file) => file.sync(),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Engine {
+	//重写失效数据比例达到Options::blob_gc_ratio的blob文件,把其中有效的value写入新的blob文件
+	//重写过的文件在下一次调用时删除,有活跃的快照时会继续保留,快照可能还会读取旧的value
+	pub fn gc_blobs(&self) -> Result<()> {
+		self.check_closed()?;
+		let _gc_lock = self.blob_store.gc_lock.lock();
+		{
+			//和创建快照互斥
+			let _lock = self.batch_commit_lock.write();
+			if !self.history.has_snapshots() {
+				self.blob_store.remove_obsolete_files();
+			}
+		}
+		let spilling_batches = &self.merge_context.spilling_batches;
+		for file_id in self.blob_store.gc_candidates(self.options.blob_gc_ratio, spilling_batches)? {
+			info!("start rewriting blob file {}", file_id);
+			if self.rewrite_blob_file(file_id)? {
+				self.blob_store.obsolete_files.lock().insert(file_id);
+			}
+		}
+		self.blob_store.sync()?;
+		self.sync()
+	}
+
+	//有value不能重写时返回false,这个文件要继续保留
+	fn rewrite_blob_file(&self, file_id: u32) -> Result<bool> {
+		let mut rewritten = true;
+		let mut offset = 0;
+		loop {
+			let ReadLogRecord { record, size } = match self.blob_store.read_record(file_id, offset) {
+				Ok(result) => result,
+				Err(Errors::ReadDataFileEOF) => break,
+				//写入value时崩溃留下的不完整数据,没有被数据文件引用
+				Err(e) if is_corrupted_record_err(&e) => match self.blob_store.next_valid_record_offset(file_id, offset) {
+					Some(next_offset) => {
+						offset = next_offset;
+						continue;
+					}
+					None => break,
+				},
+				Err(e) => return Err(e),
+			};
+			//检查和重写在同一把锁里面完成,不会覆盖掉其他的写入
+			//重写前后读到的值相同,不会产生新的版本,快照和事务感知不到
+			let _lock = self.batch_commit_lock.write();
+			if let Some((pos, base_pos, depth)) = self.find_blob_owner(&record.key, file_id, offset) {
+				match depth {
+					//内存索引直接指向这个value,过期时间保持不变
+					0 => self.rewrite_with_lock(&record.key, pos, &record.value, self.expirations.get(pos))?,
+					//value是操作数链表的基础值,合并之后的值和之前读到的一样
+					_ => {
+						//基础值过期之后合并的结果会变化,不能合并成一条永不过期的数据
+						let base = read_log_record_at(&self.active_file, &self.older_files, base_pos)?;
+						if base.expire_at > 0 && !base.is_expired(now_millis()) {
+							rewritten = false;
+						} else {
+							let value = self.get_value_by_position(pos)?;
+							self.rewrite_with_lock(&record.key, pos, &value, 0)?;
+						}
+					}
+				}
+			}
+			offset += size;
+		}
+		Ok(rewritten)
+	}
+
+	//沿着操作数链表查找引用blob文件中这个位置的记录,返回内存索引中的位置,这条记录的位置和它在链表中的深度
+	fn find_blob_owner(&self, key: &[u8], file_id: u32, offset: u64) -> Option<(LogRecordPos, LogRecordPos, usize)> {
+		let pos = self.indexer.get(key.to_vec())?;
+		//已经过期的数据不需要保留
+		if self.expirations.is_expired(pos, now_millis()) {
+			return None;
+		}
+		let mut next = Some(pos);
+		let mut depth = 0;
+		while let Some(record_pos) = next {
+			if let Some(blob_pos) = self.blob_store.pointer(record_pos) {
+				return (blob_pos.file_id == file_id && blob_pos.offset == offset).then_some((pos, record_pos, depth));
+			}
+			next = self.operand_links.prev(record_pos);
+			depth += 1;
+		}
+		None
+	}
+
+	//pos处的记录被覆盖之后,它和它之前的操作数链表引用的value都成为失效数据
+	pub(crate) fn release_blob_values(&self, pos: LogRecordPos) {
+		if self.blob_store.is_empty() {
+			return;
+		}
+		let mut next = Some(pos);
+		while let Some(record_pos) = next {
+			self.blob_store.release(record_pos);
+			next = self.operand_links.prev(record_pos);
+		}
+	}
+}
+
+fn load_blob_file_ids(dir_path: &Path) -> Result<Vec<u32>> {
+	let dir = fs::read_dir(dir_path).map_err(|_| Errors::FailedToReadDataBaseDir)?;
+	let mut file_ids = vec![];
+	for file in dir {
+		let entry = file.map_err(|_| Errors::FailedToReadDataBaseDir)?;
+		let file_os_str = entry.file_name();
+		let file_name = match file_os_str.to_str() {
+			Some(file_name) => file_name,
+			None => continue,
+		};
+		if let Some(file_id) = file_name.strip_suffix(BLOB_FILE_NAME_SUFFIX) {
+			file_ids.push(file_id.parse::<u32>().map_err(|_| Errors::DataDirectoryCorrupted)?);
+		}
+	}
+	Ok(file_ids)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use std::time::Duration;
+
+	use bytes::Bytes;
+
+	use crate::options::WriteBatchOptions;
+	use crate::util::rand_kv::get_test_key;
+
+	use super::*;
+
+	//数据目录下所有blob文件的总大小
+	fn blob_files_size(dir_path: &Path) -> u64 {
+		load_blob_file_ids(dir_path)
+			.unwrap()
+			.into_iter()
+			.map(|file_id| fs::metadata(get_blob_file_name(dir_path, file_id)).unwrap().len())
+			.sum()
+	}
+
+	fn blob_value(i: i32, version: u8) -> Bytes {
+		Bytes::from(vec![version; 4096 + i as usize])
+	}
+
+	#[test]
+	fn test_gc_blobs() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-blob"),
+			data_file_size: 256 * 1024,
+			blob_value_threshold: Some(1024),
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..100 {
+			engine.put(get_test_key(i), blob_value(i, 1)).unwrap();
+		}
+		//小value仍然写在数据文件中
+		engine.put(Bytes::from("small"), Bytes::from("value")).unwrap();
+		assert!(engine.blob_store.pointer(engine.indexer.get(b"small".to_vec()).unwrap()).is_none());
+		assert_eq!(blob_value(7, 1), engine.get(get_test_key(7)).unwrap());
+		assert!(engine.active_file.read().get_write_off() < 16 * 1024);
+
+		//覆盖和删除之后的value成为失效数据
+		for i in 0..80 {
+			engine.put(get_test_key(i), blob_value(i, 2)).unwrap();
+		}
+		for i in 80..90 {
+			engine.delete(get_test_key(i)).unwrap();
+		}
+		engine.put_with_ttl(get_test_key(95), blob_value(95, 3), Duration::from_secs(3600)).unwrap();
+		let snapshot = engine.snapshot();
+		engine.put(get_test_key(0), blob_value(0, 4)).unwrap();
+		let size_before_gc = blob_files_size(&opts.dir_path);
+		engine.gc_blobs().expect("failed to gc blobs");
+		//有快照时重写过的文件不会被删除
+		engine.gc_blobs().expect("failed to gc blobs");
+		assert!(!engine.blob_store.obsolete_files.lock().is_empty());
+		assert_eq!(blob_value(0, 2), snapshot.get(get_test_key(0)).unwrap());
+		drop(snapshot);
+		engine.gc_blobs().expect("failed to gc blobs");
+		assert!(engine.blob_store.obsolete_files.lock().is_empty());
+		assert!(blob_files_size(&opts.dir_path) < size_before_gc);
+
+		let check = |engine: &Engine| {
+			assert_eq!(blob_value(0, 4), engine.get(get_test_key(0)).unwrap());
+			for i in 1..80 {
+				assert_eq!(blob_value(i, 2), engine.get(get_test_key(i)).unwrap());
+			}
+			for i in 80..90 {
+				assert_eq!(Err(Errors::KeyNotFound), engine.get(get_test_key(i)));
+			}
+			for i in (90..100).filter(|i| *i != 95) {
+				assert_eq!(blob_value(i, 1), engine.get(get_test_key(i)).unwrap());
+			}
+			assert_eq!(blob_value(95, 3), engine.get(get_test_key(95)).unwrap());
+			assert_eq!(Bytes::from("value"), engine.get(Bytes::from("small")).unwrap());
+		};
+		check(&engine);
+		let pos = engine.indexer.get(get_test_key(95).to_vec()).unwrap();
+		assert!(engine.expirations.get(pos) > now_millis());
+
+		//重启之后重新统计失效数据,merge不会重写blob文件
+		engine.close().expect("failed to close");
+		//关闭之后目录可能已经被其他实例打开,不能再删除blob文件
+		assert_eq!(Err(Errors::DatabaseClosed), engine.gc_blobs());
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		check(&engine);
+		let size_before_merge = blob_files_size(&opts.dir_path);
+		engine.merge().expect("failed to merge");
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		check(&engine);
+		assert_eq!(size_before_merge, blob_files_size(&opts.dir_path));
+		engine.put(get_test_key(1), Bytes::from("inline")).unwrap();
+		assert_eq!(Bytes::from("inline"), engine.get(get_test_key(1)).unwrap());
+		assert!(engine.blob_store.garbage_sizes.read().values().sum::<u64>() >= blob_value(1, 2).len() as u64);
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_gc_blobs_without_new_versions() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-blob-rewrite"),
+			data_file_size: 256 * 1024,
+			blob_value_threshold: Some(1024),
+			blob_gc_ratio: 0.3,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..10 {
+			engine.put(get_test_key(i), blob_value(i, 1)).unwrap();
+		}
+		for i in 0..5 {
+			engine.put(get_test_key(i), blob_value(i, 2)).unwrap();
+		}
+		let seq_no = engine.seq_no.load(Ordering::SeqCst);
+		let pos = engine.indexer.get(get_test_key(7).to_vec()).unwrap();
+		let snapshot = engine.snapshot();
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		assert_eq!(blob_value(7, 1), txn.get(get_test_key(7)).unwrap());
+		engine.gc_blobs().expect("failed to gc blobs");
+
+		//有效的value被重写了,但是不会分配新的序列号,读过这个key的事务也不会冲突
+		assert_ne!(pos.offset, engine.indexer.get(get_test_key(7).to_vec()).unwrap().offset);
+		assert_eq!(seq_no, engine.seq_no.load(Ordering::SeqCst));
+		assert_eq!(blob_value(7, 1), snapshot.get(get_test_key(7)).unwrap());
+		txn.put(get_test_key(8), blob_value(8, 3)).unwrap();
+		txn.commit().expect("failed to commit");
+		drop(snapshot);
+		engine.gc_blobs().expect("failed to gc blobs");
+		//旧位置指向的blob文件已经被删除了,读取时改为读取索引中的新位置
+		assert_eq!(blob_value(7, 1), engine.get_value_by_position(pos).unwrap());
+		for i in 0..10 {
+			let version = match i {
+				0..5 => 2,
+				8 => 3,
+				_ => 1,
+			};
+			assert_eq!(blob_value(i, version), engine.get(get_test_key(i)).unwrap());
+		}
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_write_batch_blob_values() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-blob-batch"),
+			data_file_size: 256 * 1024,
+			blob_value_threshold: Some(1024),
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let wb = engine.new_write_batch(WriteBatchOptions::default());
+		for i in 0..10 {
+			wb.put(get_test_key(i), blob_value(i, 1)).unwrap();
+		}
+		wb.put(Bytes::from("small"), Bytes::from("value")).unwrap();
+		wb.commit().unwrap();
+
+		//spill模式下大value在写入时就进入了blob文件,提交之前不能gc
+		let spill_wb = engine.new_write_batch(WriteBatchOptions {
+			spill_to_disk: true,
+			..Default::default()
+		});
+		for i in 0..10 {
+			spill_wb.put(get_test_key(i), blob_value(i, 2)).unwrap();
+		}
+		spill_wb.put(get_test_key(10), blob_value(10, 2)).unwrap();
+		spill_wb.put(get_test_key(10), blob_value(10, 3)).unwrap();
+		assert_eq!(blob_value(10, 3), spill_wb.get(get_test_key(10)).unwrap());
+		assert_eq!(Err(Errors::SpilledBatchInProgress), engine.gc_blobs());
+		spill_wb.commit().unwrap();
+
+		let check = |engine: &Engine| {
+			for i in 0..10 {
+				assert_eq!(blob_value(i, 2), engine.get(get_test_key(i)).unwrap());
+			}
+			assert_eq!(blob_value(10, 3), engine.get(get_test_key(10)).unwrap());
+			assert_eq!(Bytes::from("value"), engine.get(Bytes::from("small")).unwrap());
+		};
+		check(&engine);
+		assert!(engine.active_file.read().get_write_off() < 4 * 1024);
+		let pos = engine.indexer.get(get_test_key(0).to_vec()).unwrap();
+		assert!(engine.blob_store.pointer(pos).is_some());
+		//第一个batch写入的value都被覆盖了
+		engine.gc_blobs().expect("failed to gc blobs");
+		engine.gc_blobs().expect("failed to gc blobs");
+		check(&engine);
+
+		//重启之后从事务记录中恢复value在blob文件中的位置
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		check(&engine);
+		let pos = engine.indexer.get(get_test_key(0).to_vec()).unwrap();
+		assert!(engine.blob_store.pointer(pos).is_some());
+		engine.gc_blobs().expect("failed to gc blobs");
+		check(&engine);
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+}
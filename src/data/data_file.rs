@@ -6,12 +6,21 @@ use parking_lot::RwLock;
 use prost::{decode_length_delimiter, length_delimiter_len};
 
 use crate::data::log_record::{
-	LogRecord, LogRecordType, max_log_record_header_size, ReadLogRecord,
+	decode_type_byte, decompress, LogRecord, LogRecordPos, LogRecordType, max_log_record_header_size, ReadLogRecord,
+	RECORD_FLAG_ENCRYPTED, RECORD_FLAG_EXPIRE,
 };
+use crate::encryption::{decrypt, ENCRYPTION_TRAILER_SIZE, KeyProvider};
 use crate::errors::{Errors, Result};
 use crate::fio::{IOManager, new_io_manager};
+use crate::options::Compression;
 
 pub const DATA_FILE_NAME_SUFFIX: &str = ".data";
+//存放大value的blob文件
+pub const BLOB_FILE_NAME_SUFFIX: &str = ".blob";
+//标识merge完成的文件,里面只有一条记录
+pub const MERGE_FINISHED_FILE_NAME: &str = "merge-finished";
+//merge时生成的索引文件,存储key和其对应的LogRecordPos
+pub const HINT_FILE_NAME: &str = "hint-index";
 
 //DataFile是对FILEIO的进一层封装,加入了file_id,偏移量等属性
 pub struct DataFile {
@@ -20,6 +29,10 @@ pub struct DataFile {
 	write_off: Arc<RwLock<u64>>,
 	//当前写偏移,记录该数据文件写到哪个位置了
 	io_manager: Box<dyn IOManager>,//目前只实现了FILEIO(对File结构体的封装)
+	size: Arc<RwLock<u64>>,
+	//打开时文件的大小加上之后写入的数据,读取时用来检查记录的长度,不需要每次都获取文件的元数据
+	key_provider: Option<Arc<dyn KeyProvider>>,
+	//读取加密的记录时用来查找密钥,写入hint记录时用来加密
 }
 
 impl DataFile {
@@ -32,14 +45,93 @@ impl DataFile {
 			DataFile {
 				file_id: Arc::new(RwLock::new(file_id)),
 				write_off: Arc::new(RwLock::new(0)),
+				size: Arc::new(RwLock::new(io_manager.size())),
 				io_manager,
+				key_provider: None,
 			}
 		})
 	}
+	//设置了密钥的数据文件才能读取加密的记录
+	pub fn with_key_provider(mut self, key_provider: Option<Arc<dyn KeyProvider>>) -> DataFile {
+		self.key_provider = key_provider;
+		self
+	}
+	//新建blob文件,和数据文件一样使用LogRecord的格式存储,key为实际的key
+	pub fn new_blob_file(dir_path: &Path, file_id: u32) -> Result<DataFile> {
+		let file_name = get_blob_file_name(dir_path, file_id);
+		let io_manager = new_io_manager(file_name)?;
+		Ok(DataFile {
+			file_id: Arc::new(RwLock::new(file_id)),
+			write_off: Arc::new(RwLock::new(0)),
+			size: Arc::new(RwLock::new(io_manager.size())),
+			io_manager,
+			key_provider: None,
+		})
+	}
+	//新建标识merge完成的文件,这个文件不属于数据文件,file_id没有意义
+	pub fn new_merge_fin_file(dir_path: &Path) -> Result<DataFile> {
+		let file_name = dir_path.join(MERGE_FINISHED_FILE_NAME);
+		let io_manager = new_io_manager(file_name)?;
+		Ok(DataFile {
+			file_id: Arc::new(RwLock::new(0)),
+			write_off: Arc::new(RwLock::new(0)),
+			size: Arc::new(RwLock::new(io_manager.size())),
+			io_manager,
+			key_provider: None,
+		})
+	}
+	//新建hint文件,和数据文件一样使用LogRecord的格式存储,file_id没有意义
+	pub fn new_hint_file(dir_path: &Path) -> Result<DataFile> {
+		let file_name = dir_path.join(HINT_FILE_NAME);
+		let io_manager = new_io_manager(file_name)?;
+		Ok(DataFile {
+			file_id: Arc::new(RwLock::new(0)),
+			write_off: Arc::new(RwLock::new(0)),
+			size: Arc::new(RwLock::new(io_manager.size())),
+			io_manager,
+			key_provider: None,
+		})
+	}
+	//写入一条hint记录,key为实际的key,value为编码后的LogRecordPos,过期时间和数据保持一致
+	//value在blob文件中的数据,后面再追加其在blob文件中的位置
+	pub fn write_hint_record(
+		&self,
+		key: Vec<u8>,
+		pos: LogRecordPos,
+		expire_at: u64,
+		blob_pos: Option<LogRecordPos>,
+	) -> Result<()> {
+		let mut value = pos.encode();
+		let mut rec_type = LogRecordType::NORMAL;
+		if let Some(blob_pos) = blob_pos {
+			value.extend(blob_pos.encode());
+			rec_type = LogRecordType::BLOB_POINTER;
+		}
+		let hint_record = LogRecord {
+			key,
+			value,
+			rec_type,
+			expire_at,
+		};
+		self.write(&hint_record.encode_with(Compression::None, self.key_provider.as_deref())?)?;
+		Ok(())
+	}
+	//操作数记录的位置,加载时要接到之前的记录后面
+	pub fn write_operand_hint_record(&self, key: Vec<u8>, pos: LogRecordPos) -> Result<()> {
+		let hint_record = LogRecord {
+			key,
+			value: pos.encode(),
+			rec_type: LogRecordType::MERGE_OPERAND,
+			expire_at: 0,
+		};
+		self.write(&hint_record.encode_with(Compression::None, self.key_provider.as_deref())?)?;
+		Ok(())
+	}
 	pub fn write(&self, buf: &[u8]) -> Result<usize> {
 		let n_bytes = self.io_manager.write(buf)?;
 		let mut wg = self.write_off.write();
 		*wg += n_bytes as u64;
+		*self.size.write() += n_bytes as u64;
 		Ok(n_bytes)
 	}
 	pub fn set_write_off(&self, offset: u64) {
@@ -57,6 +149,38 @@ impl DataFile {
 	pub fn sync(&self) -> Result<()> {
 		self.io_manager.sync()
 	}
+	//数据文件在磁盘上的实际大小
+	pub fn file_size(&self) -> u64 {
+		self.io_manager.size()
+	}
+	//从offset之后逐字节查找下一条可以正确解析的记录,用于跳过损坏的数据
+	//分块读取文件,只有type合法的位置才尝试解析,避免每个字节都读一次文件
+	pub fn next_valid_record_offset(&self, offset: u64) -> Option<u64> {
+		let file_size = self.file_size();
+		let mut chunk = vec![0u8; 64 * 1024];
+		let mut chunk_start = offset + 1;
+		while chunk_start < file_size {
+			let n_bytes = self.io_manager.read(&mut chunk, chunk_start).ok()?;
+			if n_bytes == 0 {
+				return None;
+			}
+			for (i, b) in chunk[..n_bytes].iter().enumerate() {
+				let next = chunk_start + i as u64;
+				if LogRecordType::is_valid(*b) && self.read_log_record(next).is_ok() {
+					return Some(next);
+				}
+			}
+			chunk_start += n_bytes as u64;
+		}
+		None
+	}
+	//把文件截断到offset处,同时更新写偏移
+	pub fn truncate(&self, offset: u64) -> Result<()> {
+		self.io_manager.truncate(offset)?;
+		*self.size.write() = offset;
+		self.set_write_off(offset);
+		Ok(())
+	}
 	pub fn read_log_record(&self, offset: u64) -> Result<ReadLogRecord> {
 		//也可以先判断offset是否合法
 		
@@ -65,35 +189,73 @@ impl DataFile {
 		let mut header_buf = BytesMut::zeroed(max_log_record_header_size());
 		//把磁盘文件里面的type + key_size + value_size读到header_buf里面
 		self.io_manager.read(&mut header_buf, offset)?;
+		//header_buf本身要用来计算crc,这里用一个切片来解析
+		let mut header = &header_buf[..];
 		//取出type,把crc放在了最后一个字节,type在第一个字节
-		let rec_type = header_buf.get_u8();
+		let rec_type = header.get_u8();
 		//取出key和value的长度
-		let key_size = decode_length_delimiter(&mut header_buf).unwrap(); //应该是里面维护了一个cursor
-		let value_size = decode_length_delimiter(&mut header_buf).unwrap();
+		//header损坏时长度可能无法解码,或者解码出超过u32范围的长度
+		let mut decode_size = || match decode_length_delimiter(&mut header) {
+			Ok(size) if size <= u32::MAX as usize => Ok(size),
+			_ => Err(Errors::InvalidLogRecordHeader),
+		};
+		let key_size = decode_size()?; //应该是里面维护了一个cursor
+		let value_size = decode_size()?;
 		//如果key和value的长度都为0,则说明读取到了文件的末尾,直接返回
 		if key_size == 0 && value_size == 0 {
 			return Err(Errors::ReadDataFileEOF);
 		}
+		//type字节中的标识位,决定header中有没有过期时间,type本身等crc校验通过之后再解析
+		let mut expire_at = 0;
+		let mut expire_at_len = 0;
+		if rec_type & RECORD_FLAG_EXPIRE != 0 {
+			expire_at = decode_length_delimiter(&mut header).map_err(|_| Errors::InvalidLogRecordHeader)? as u64;
+			expire_at_len = length_delimiter_len(expire_at as usize);
+		}
 		//根据key和value的size读取实际的key和value
 		//获取实际的header大小,type 1字节,加上key和value的size编码后的长度
 		let actual_header_size =
-			length_delimiter_len(key_size) + length_delimiter_len(value_size) + 1;
+			length_delimiter_len(key_size) + length_delimiter_len(value_size) + expire_at_len + 1;
+		//记录的长度超出了文件的大小,说明记录不完整或者header已经损坏,不能按照这个长度分配内存
+		//文件可能被别的DataFile写入过,超出记下的大小时再获取一次实际的大小确认
+		let record_end = offset + (actual_header_size + key_size + value_size + 4) as u64;
+		if record_end > *self.size.read() && record_end > self.io_manager.size() {
+			return Err(Errors::IncompleteLogRecord);
+		}
 		let mut kv_buf = BytesMut::zeroed(key_size + value_size + 4); //最后4字节为CRC校验值
 		//读出key,value,crc部分到kv_buf里面
-		self.io_manager
+		let n_bytes = self
+			.io_manager
 			.read(&mut kv_buf, offset + actual_header_size as u64)?;
-		//构造LogRecord
-		let log_record = LogRecord {
-			key: kv_buf.get(..key_size).unwrap().to_vec(),
-			value: kv_buf.get(key_size..kv_buf.len() - 4).unwrap().to_vec(),
-			rec_type: LogRecordType::from_u8(rec_type),
-		};
-		//得到CRC的值
-		kv_buf.advance(key_size + value_size);
+		//文件里剩下的数据不够一条完整的记录,说明这条记录没有写完
+		if n_bytes < kv_buf.len() {
+			return Err(Errors::IncompleteLogRecord);
+		}
+		//直接对读出来的原始数据计算crc,校验通过之后再解析type,损坏的type不会被当作合法的记录
+		let mut hasher = crc32fast::Hasher::new();
+		hasher.update(&header_buf[..actual_header_size]);
+		hasher.update(&kv_buf[..key_size + value_size]);
 		//比较一下从文件里面读取到的crc值是不是和计算出来的一样
-		if kv_buf.get_u32() != log_record.get_crc() {
+		if (&kv_buf[key_size + value_size..]).get_u32() != hasher.finalize() {
 			return Err(Errors::InvalidLogRecordCrc);
 		}
+		//构造LogRecord,加密的记录先解密,压缩过的value再解压
+		let (rec_type, flags) = decode_type_byte(rec_type)?;
+		let mut value_end = key_size + value_size;
+		if flags & RECORD_FLAG_ENCRYPTED != 0 {
+			if value_size < ENCRYPTION_TRAILER_SIZE {
+				return Err(Errors::InvalidLogRecordHeader);
+			}
+			value_end -= ENCRYPTION_TRAILER_SIZE;
+			let (payload, trailer) = kv_buf[..key_size + value_size].split_at_mut(value_end);
+			decrypt(self.key_provider.as_deref(), &header_buf[..actual_header_size], payload, trailer)?;
+		}
+		let log_record = LogRecord {
+			key: kv_buf.get(..key_size).unwrap().to_vec(),
+			value: decompress(flags, kv_buf.get(key_size..value_end).unwrap().to_vec())?,
+			rec_type,
+			expire_at,
+		};
 		Ok(ReadLogRecord {
 			record: log_record,
 			size: (actual_header_size + key_size + value_size + 4) as u64,
@@ -101,15 +263,22 @@ impl DataFile {
 	}
 }
 
-fn get_data_file_name(dir_path: &Path, file_id: u32) -> PathBuf {
+pub(crate) fn get_data_file_name(dir_path: &Path, file_id: u32) -> PathBuf {
 	let name = std::format!("{:09}{}", file_id, DATA_FILE_NAME_SUFFIX);
 	dir_path.to_path_buf().join(name)
 }
 
+pub(crate) fn get_blob_file_name(dir_path: &Path, file_id: u32) -> PathBuf {
+	let name = std::format!("{:09}{}", file_id, BLOB_FILE_NAME_SUFFIX);
+	dir_path.to_path_buf().join(name)
+}
+
 #[cfg(test)]
 mod test {
 	use std::fs;
 
+	use crate::data::log_record::decode_log_record_pos;
+
 	use super::*;
 
 	#[test]
@@ -179,6 +348,7 @@ mod test {
 			key: "name".as_bytes().to_vec(),
 			value: "bitcask-rs-kv".as_bytes().to_vec(),
 			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
 		};
 		let write_res1 = data_file1.write(&enc1.encode());
 		assert!(write_res1.is_ok());
@@ -196,6 +366,7 @@ mod test {
 			key: "name".as_bytes().to_vec(),
 			value: "new-value".as_bytes().to_vec(),
 			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
 		};
 		let write_res2 = data_file1.write(&enc2.encode());
 		assert!(write_res2.is_ok());
@@ -212,6 +383,7 @@ mod test {
 			key: "name".as_bytes().to_vec(),
 			value: Default::default(),
 			rec_type: LogRecordType::DELETED,
+			expire_at: 0,
 		};
 		let write_res3 = data_file1.write(&enc3.encode());
 		assert!(write_res3.is_ok());
@@ -223,4 +395,82 @@ mod test {
 		assert_eq!(enc3.rec_type, read_enc3.rec_type);
 		fs::remove_file(get_data_file_name(&dir_path, 700)).unwrap();
 	}
+
+	#[test]
+	fn test_hint_file_write_and_read() {
+		let dir_path = PathBuf::from("/tmp/bitcask-rs-hint-file");
+		fs::create_dir_all(&dir_path).unwrap();
+		let hint_file = DataFile::new_hint_file(&dir_path).unwrap();
+		let pos = LogRecordPos {
+			file_id: 3,
+			offset: 1024,
+			size: 32,
+		};
+		assert!(hint_file.write_hint_record("name".as_bytes().to_vec(), pos, 0, None).is_ok());
+		assert!(hint_file.sync().is_ok());
+
+		let ReadLogRecord { record, .. } = hint_file.read_log_record(0).unwrap();
+		assert_eq!("name".as_bytes().to_vec(), record.key);
+		let read_pos = decode_log_record_pos(record.value).unwrap();
+		assert_eq!(3, read_pos.file_id);
+		assert_eq!(1024, read_pos.offset);
+		assert_eq!(32, read_pos.size);
+		assert_eq!(Errors::ReadDataFileEOF, hint_file.read_log_record(hint_file.get_write_off()).err().unwrap());
+		fs::remove_dir_all(dir_path).unwrap();
+	}
+
+	//简单的xorshift伪随机数生成器,测试里不需要引入rand
+	fn xorshift(state: &mut u64) -> u64 {
+		*state ^= *state << 13;
+		*state ^= *state >> 7;
+		*state ^= *state << 17;
+		*state
+	}
+
+	#[test]
+	fn test_read_log_record_with_random_bytes() {
+		let dir_path = PathBuf::from("/tmp/bitcask-rs-fuzz");
+		fs::create_dir_all(&dir_path).unwrap();
+		let data_file = DataFile::new(&dir_path, 0).unwrap();
+		let valid = LogRecord {
+			key: "name".as_bytes().to_vec(),
+			value: "bitcask-rs-kv".as_bytes().to_vec(),
+			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
+		}
+		.encode();
+		let mut state = 0x2545_f491_4f6c_dd1d;
+		for round in 0..2000 {
+			//一半是完全随机的数据,一半是在合法记录上随机改几个字节
+			let mut buf = if round % 2 == 0 {
+				let len = xorshift(&mut state) % 64;
+				(0..len).map(|_| xorshift(&mut state) as u8).collect::<Vec<u8>>()
+			} else {
+				let mut buf = valid.clone();
+				for _ in 0..=xorshift(&mut state) % 3 {
+					let i = (xorshift(&mut state) % buf.len() as u64) as usize;
+					buf[i] = xorshift(&mut state) as u8;
+				}
+				buf
+			};
+			//随机截掉末尾的数据,模拟没写完的记录
+			if round % 3 == 0 && !buf.is_empty() {
+				let len = (xorshift(&mut state) % buf.len() as u64) as usize;
+				buf.truncate(len);
+			}
+			data_file.truncate(0).unwrap();
+			data_file.write(&buf).unwrap();
+			//任意位置读取都只能返回记录或者错误,不能panic
+			for offset in 0..=buf.len() as u64 {
+				if let Ok(ReadLogRecord { record, size }) = data_file.read_log_record(offset) {
+					assert!(offset + size <= buf.len() as u64);
+					let _ = crate::batch::parse_log_record_key(&record.key);
+					let _ = decode_log_record_pos(record.value);
+				}
+			}
+			let _ = crate::batch::parse_log_record_key(&buf);
+			let _ = decode_log_record_pos(buf);
+		}
+		fs::remove_dir_all(dir_path).unwrap();
+	}
 }
@@ -1,8 +1,15 @@
+use std::borrow::Cow;
+
 use bytes::{BufMut, BytesMut};
-use prost::{encode_length_delimiter, length_delimiter_len};
+use prost::{decode_length_delimiter, encode_length_delimiter, length_delimiter_len};
+
+use crate::encryption::{encrypt, ENCRYPTION_TRAILER_SIZE, KeyProvider};
+use crate::errors::{Errors, Result};
+use crate::options::Compression;
 
 //logRecord写入到数据文件的记录.之所以叫日志,因为数据文件中数据是追加写入的,类似于日志的格式
 #[derive(PartialEq, Copy, Clone, Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum LogRecordType {
 	//正常put的数据
 	NORMAL = 1,
@@ -10,17 +17,51 @@ pub enum LogRecordType {
 	DELETED = 2,
 	//事务提交的标识,transaction
 	TXN_FINISHED = 3,
+	//merge_value写入的操作数,读取时用合并操作符合并到之前的值上面
+	MERGE_OPERAND = 4,
+	//value存放在blob文件中的数据,记录的value是其在blob文件中的位置
+	BLOB_POINTER = 5,
 }
 
+//type字节的高位用来标识header中的可选字段,低位是LogRecordType
+//header中有过期时间
+pub(crate) const RECORD_FLAG_EXPIRE: u8 = 0x80;
+//value使用lz4压缩
+const RECORD_FLAG_LZ4: u8 = 0x40;
+//value使用snappy压缩
+const RECORD_FLAG_SNAPPY: u8 = 0x20;
+//key和value加密过,value后面追加了密钥id,nonce和tag
+pub(crate) const RECORD_FLAG_ENCRYPTED: u8 = 0x10;
+const RECORD_TYPE_MASK: u8 = 0x0f;
+
 impl LogRecordType {
-	pub fn from_u8(v: u8) -> Self {
+	pub fn from_u8(v: u8) -> Result<Self> {
 		match v {
-			1 => LogRecordType::NORMAL,
-			2 => LogRecordType::DELETED,
-			3 => LogRecordType::TXN_FINISHED,
-			_ => panic!("Unknown LogRecord type"),
+			1 => Ok(LogRecordType::NORMAL),
+			2 => Ok(LogRecordType::DELETED),
+			3 => Ok(LogRecordType::TXN_FINISHED),
+			4 => Ok(LogRecordType::MERGE_OPERAND),
+			5 => Ok(LogRecordType::BLOB_POINTER),
+			_ => Err(Errors::UnknownLogRecordType),
 		}
 	}
+	//判断一个字节是不是合法的type字节
+	pub fn is_valid(v: u8) -> bool {
+		decode_type_byte(v).is_ok()
+	}
+}
+
+//解析type字节,返回LogRecordType和标识位
+pub(crate) fn decode_type_byte(v: u8) -> Result<(LogRecordType, u8)> {
+	let flags = v & !RECORD_TYPE_MASK;
+	if flags & !(RECORD_FLAG_EXPIRE | RECORD_FLAG_LZ4 | RECORD_FLAG_SNAPPY | RECORD_FLAG_ENCRYPTED) != 0 {
+		return Err(Errors::UnknownLogRecordType);
+	}
+	//value只会用一种算法压缩
+	if flags & RECORD_FLAG_LZ4 != 0 && flags & RECORD_FLAG_SNAPPY != 0 {
+		return Err(Errors::UnknownLogRecordType);
+	}
+	Ok((LogRecordType::from_u8(v & RECORD_TYPE_MASK)?, flags))
 }
 
 #[derive(Debug)]
@@ -28,6 +69,8 @@ pub struct LogRecord {
 	pub(crate) key: Vec<u8>,
 	pub(crate) value: Vec<u8>,
 	pub(crate) rec_type: LogRecordType,
+	//过期时间,unix时间戳(毫秒),0表示永不过期
+	pub(crate) expire_at: u64,
 }
 //暂存事务的信息和pos
 #[derive(Debug)]
@@ -38,49 +81,113 @@ pub struct TransactionRecord{
 impl LogRecord {
 	// encode 对 LogRecord 进行编码，返回字节数组及长度
 	//
-	//	+-------------+--------------+-------------+--------------+-------------+-------------+
-	//	|  type 类型   |    key size |   value size |      key    |      value   |  crc 校验值  |
-	//	+-------------+-------------+--------------+--------------+-------------+-------------+
-	//	    1字节        变长（最大5）   变长（最大5）        变长           变长           4字节
+	//	+-------------+--------------+-------------+--------------+--------------+-------------+-------------+
+	//	|  type 类型   |    key size |   value size |   过期时间    |      key    |      value   |  crc 校验值  |
+	//	+-------------+-------------+--------------+--------------+--------------+-------------+-------------+
+	//	    1字节        变长（最大5）   变长（最大5）  变长(可选,最大10)      变长           变长           4字节
+	//
+	//	只有设置了过期时间的记录才有过期时间字段,type字节中用RECORD_FLAG_EXPIRE标识
+	//	压缩过的value中value size是压缩之后的长度,type字节中标识了压缩算法
+	//	加密的记录中key和value是密文,value后面追加了密钥id,nonce和tag,value size包括这部分数据
 	pub fn encode(&self) -> Vec<u8> {
-		//存放编码数据的字节数组
-		self.encode_and_get_crc().0
+		//存放编码数据的字节数组,不加密时编码不会失败
+		self.encode_and_get_crc(Compression::None, None).unwrap().0
+	}
+	//按照compression压缩value,有key_provider时再用当前的密钥加密key和value
+	pub fn encode_with(&self, compression: Compression, key_provider: Option<&dyn KeyProvider>) -> Result<Vec<u8>> {
+		Ok(self.encode_and_get_crc(compression, key_provider)?.0)
 	}
-	pub fn encode_and_get_crc(&self) -> (Vec<u8>, u32) {
+	pub fn encode_and_get_crc(&self, compression: Compression, key_provider: Option<&dyn KeyProvider>) -> Result<(Vec<u8>, u32)> {
+		//压缩之后没有变小的value直接写入原始数据
+		let (value, compress_flag) = match compress(compression, &self.value) {
+			Some((value, flag)) => (Cow::Owned(value), flag),
+			None => (Cow::Borrowed(self.value.as_slice()), 0),
+		};
+		let (value_len, encrypt_flag) = match key_provider {
+			Some(_) => (value.len() + ENCRYPTION_TRAILER_SIZE, RECORD_FLAG_ENCRYPTED),
+			None => (value.len(), 0),
+		};
 		//存放编码数据的字节数组,使用第三方的crate(为了更强大的接口)
 		let mut buf = BytesMut::new();
-		buf.reserve(self.encode_length());
+		buf.reserve(self.encode_length(value_len));
 
 		//第一个字节存放Type
-		buf.put_u8(self.rec_type as u8);
+		buf.put_u8(self.rec_type as u8 | self.flags() | compress_flag | encrypt_flag);
 
 		//借助prost库存储key和value的长度
 		encode_length_delimiter(self.key.len(), &mut buf).unwrap();
-		encode_length_delimiter(self.value.len(), &mut buf).unwrap();
+		encode_length_delimiter(value_len, &mut buf).unwrap();
+		if self.expire_at > 0 {
+			encode_length_delimiter(self.expire_at as usize, &mut buf).unwrap();
+		}
+		let header_len = buf.len();
 		buf.extend_from_slice(&self.key);
-		buf.extend_from_slice(&self.value);
+		buf.extend_from_slice(&value);
+		//header作为附加数据,读取时header被篡改也无法解密
+		if let Some(key_provider) = key_provider {
+			let (header, payload) = buf.split_at_mut(header_len);
+			let trailer = encrypt(key_provider, header, payload)?;
+			buf.extend_from_slice(&trailer);
+		}
 
 		//计算出crc校验值
 		let mut hasher = crc32fast::Hasher::new();
 		hasher.update(&buf);
 		let crc = hasher.finalize();
 		buf.put_u32(crc);
-		(buf.to_vec(), crc)
+		Ok((buf.to_vec(), crc))
 	}
+	#[allow(dead_code)]
 	pub fn get_crc(&self) -> u32 {
-		self.encode_and_get_crc().1
+		self.encode_and_get_crc(Compression::None, None).unwrap().1
+	}
+	//过期时间为now(毫秒)时已经过期了
+	pub(crate) fn is_expired(&self, now: u64) -> bool {
+		self.expire_at > 0 && self.expire_at <= now
 	}
-	//计算log_record编码后的长度
-	fn encode_length(&self) -> usize {
+	fn flags(&self) -> u8 {
+		match self.expire_at > 0 {
+			true => RECORD_FLAG_EXPIRE,
+			false => 0,
+		}
+	}
+	//计算log_record编码后的长度,value_len是压缩之后的长度
+	fn encode_length(&self, value_len: usize) -> usize {
+		let expire_at_len = match self.expire_at > 0 {
+			true => length_delimiter_len(self.expire_at as usize),
+			false => 0,
+		};
 		std::mem::size_of::<u8>() //type大小1字节
 			+ length_delimiter_len(self.key.len())
-			+ length_delimiter_len(self.value.len())
+			+ length_delimiter_len(value_len)
+			+ expire_at_len
 			+ self.key.len()
-			+ self.value.len()
+			+ value_len
 			+ 4 //crc大小4字节
 	}
 }
 
+//压缩value,返回压缩之后的数据和type字节中的标识,不需要压缩时返回None
+fn compress(compression: Compression, value: &[u8]) -> Option<(Vec<u8>, u8)> {
+	let (compressed, flag) = match compression {
+		Compression::None => return None,
+		Compression::Lz4 => (lz4_flex::compress_prepend_size(value), RECORD_FLAG_LZ4),
+		Compression::Snappy => (snap::raw::Encoder::new().compress_vec(value).ok()?, RECORD_FLAG_SNAPPY),
+	};
+	(compressed.len() < value.len()).then_some((compressed, flag))
+}
+
+//根据type字节中的标识解压value,没有压缩过的value原样返回
+pub(crate) fn decompress(flags: u8, value: Vec<u8>) -> Result<Vec<u8>> {
+	if flags & RECORD_FLAG_LZ4 != 0 {
+		return lz4_flex::decompress_size_prepended(&value).map_err(|_| Errors::FailedToDecompressValue);
+	}
+	if flags & RECORD_FLAG_SNAPPY != 0 {
+		return snap::raw::Decoder::new().decompress_vec(&value).map_err(|_| Errors::FailedToDecompressValue);
+	}
+	Ok(value)
+}
+
 //数据位置索引信息，描述数据存储到了什么位置
 #[derive(Debug, Copy, Clone)]
 pub struct LogRecordPos {
@@ -88,6 +195,47 @@ pub struct LogRecordPos {
 	pub(crate) file_id: u32,
 	//文件id
 	pub(crate) offset: u64, //文件偏移
+	pub(crate) size: u32, //记录编码后在磁盘上占据的大小
+}
+
+impl LogRecordPos {
+	//编码后写入hint文件,file_id和offset都使用变长编码
+	pub fn encode(&self) -> Vec<u8> {
+		let mut buf = BytesMut::new();
+		encode_length_delimiter(self.file_id as usize, &mut buf).unwrap();
+		encode_length_delimiter(self.offset as usize, &mut buf).unwrap();
+		encode_length_delimiter(self.size as usize, &mut buf).unwrap();
+		buf.to_vec()
+	}
+}
+
+//从hint文件中读出的value解码为LogRecordPos
+pub fn decode_log_record_pos(pos: Vec<u8>) -> Result<LogRecordPos> {
+	decode_pos_from(&mut &pos[..])
+}
+
+//blob数据的hint记录中依次存放数据文件中的位置和blob文件中的位置
+pub(crate) fn decode_blob_hint(value: Vec<u8>) -> Result<(LogRecordPos, LogRecordPos)> {
+	let mut buf = &value[..];
+	let pos = decode_pos_from(&mut buf)?;
+	let blob_pos = decode_pos_from(&mut buf)?;
+	Ok((pos, blob_pos))
+}
+
+fn decode_pos_from(buf: &mut &[u8]) -> Result<LogRecordPos> {
+	let mut decode = || decode_length_delimiter(&mut *buf).map_err(|_| Errors::InvalidLogRecordPos);
+	let file_id = decode()?;
+	let offset = decode()?;
+	let size = decode()?;
+	//file_id和size编码前都是u32,超出范围说明hint文件损坏了
+	if file_id > u32::MAX as usize || size > u32::MAX as usize {
+		return Err(Errors::InvalidLogRecordPos);
+	}
+	Ok(LogRecordPos {
+		file_id: file_id as u32,
+		offset: offset as u64,
+		size: size as u32,
+	})
 }
 
 pub struct ReadLogRecord {
@@ -99,7 +247,7 @@ pub struct ReadLogRecord {
 #[inline]
 pub fn max_log_record_header_size() -> usize {
 	//length_delimiter对于不同大小usize值编码后的长度不同
-	std::mem::size_of::<u8>() + length_delimiter_len(u32::MAX as usize) * 2
+	std::mem::size_of::<u8>() + length_delimiter_len(u32::MAX as usize) * 2 + length_delimiter_len(u64::MAX as usize)
 }
 
 #[cfg(test)]
@@ -113,6 +261,7 @@ mod tests {
 			key: "name".as_bytes().to_vec(),
 			value: "bitcask-rs".as_bytes().to_vec(),
 			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
 		};
 		let enc1 = rec1.encode();
 		assert!(enc1.len() > 5);
@@ -123,6 +272,7 @@ mod tests {
 			key: "name".as_bytes().to_vec(),
 			value: Default::default(),
 			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
 		};
 		let enc2 = rec2.encode();
 		assert!(enc2.len() > 5);
@@ -133,9 +283,88 @@ mod tests {
 			key: "name".as_bytes().to_vec(),
 			value: "bitcask-rs".as_bytes().to_vec(),
 			rec_type: LogRecordType::DELETED,
+			expire_at: 0,
 		};
 		let enc3 = rec3.encode();
 		assert!(enc3.len() > 5);
 		assert_eq!(1867197446, rec3.get_crc());
 	}
+
+	#[test]
+	fn test_log_record_pos_encode_and_decode() {
+		let pos = LogRecordPos {
+			file_id: 1024,
+			offset: 1 << 40,
+			size: 100,
+		};
+		let dec = decode_log_record_pos(pos.encode()).unwrap();
+		assert_eq!(pos.file_id, dec.file_id);
+		assert_eq!(pos.offset, dec.offset);
+		assert_eq!(pos.size, dec.size);
+
+		//损坏的数据返回错误而不是panic
+		assert_eq!(Errors::InvalidLogRecordPos, decode_log_record_pos(vec![]).err().unwrap());
+		assert_eq!(Errors::InvalidLogRecordPos, decode_log_record_pos(vec![0xff; 8]).err().unwrap());
+		let mut enc = pos.encode();
+		enc.pop();
+		assert_eq!(Errors::InvalidLogRecordPos, decode_log_record_pos(enc).err().unwrap());
+
+		let blob_pos = LogRecordPos {
+			file_id: 3,
+			offset: 4096,
+			size: 20 << 20,
+		};
+		let mut enc = pos.encode();
+		enc.extend(blob_pos.encode());
+		let (dec, dec_blob) = decode_blob_hint(enc).unwrap();
+		assert_eq!(pos.offset, dec.offset);
+		assert_eq!(blob_pos.size, dec_blob.size);
+		assert_eq!(Errors::InvalidLogRecordPos, decode_blob_hint(pos.encode()).err().unwrap());
+	}
+
+	#[test]
+	fn test_log_record_encode_with_compression() {
+		let record = LogRecord {
+			key: "name".as_bytes().to_vec(),
+			value: "bitcask-rs".repeat(100).into_bytes(),
+			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
+		};
+		let raw = record.encode();
+		for (compression, flag) in [(Compression::Lz4, RECORD_FLAG_LZ4), (Compression::Snappy, RECORD_FLAG_SNAPPY)] {
+			let enc = record.encode_with(compression, None).unwrap();
+			assert!(enc.len() < raw.len());
+			assert_eq!(Ok((LogRecordType::NORMAL, flag)), decode_type_byte(enc[0]));
+		}
+		assert_eq!(raw, record.encode_with(Compression::None, None).unwrap());
+
+		//压缩之后没有变小的value不压缩
+		let short = LogRecord {
+			key: "name".as_bytes().to_vec(),
+			value: "bitcask-rs".as_bytes().to_vec(),
+			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
+		};
+		assert_eq!(short.encode(), short.encode_with(Compression::Lz4, None).unwrap());
+
+		assert_eq!(Ok(record.value.clone()), decompress(RECORD_FLAG_LZ4, lz4_flex::compress_prepend_size(&record.value)));
+		assert_eq!(Ok(record.value.clone()), decompress(0, record.value.clone()));
+		assert_eq!(Err(Errors::FailedToDecompressValue), decompress(RECORD_FLAG_SNAPPY, record.value.clone()));
+	}
+
+	#[test]
+	fn test_log_record_type_from_u8() {
+		assert_eq!(Ok(LogRecordType::NORMAL), LogRecordType::from_u8(1));
+		assert_eq!(Ok(LogRecordType::TXN_FINISHED), LogRecordType::from_u8(3));
+		assert_eq!(Ok(LogRecordType::MERGE_OPERAND), LogRecordType::from_u8(4));
+		assert_eq!(Ok(LogRecordType::BLOB_POINTER), LogRecordType::from_u8(5));
+		assert_eq!(Ok((LogRecordType::NORMAL, RECORD_FLAG_EXPIRE)), decode_type_byte(0x81));
+		assert!(LogRecordType::is_valid(0x82));
+		assert_eq!(Ok((LogRecordType::NORMAL, RECORD_FLAG_LZ4)), decode_type_byte(0x41));
+		assert_eq!(Err(Errors::UnknownLogRecordType), decode_type_byte(0x61));
+		assert_eq!(Ok((LogRecordType::NORMAL, RECORD_FLAG_ENCRYPTED)), decode_type_byte(0x11));
+		assert!(!LogRecordType::is_valid(0x80));
+		assert_eq!(Err(Errors::UnknownLogRecordType), LogRecordType::from_u8(0));
+		assert_eq!(Err(Errors::UnknownLogRecordType), LogRecordType::from_u8(0xff));
+	}
 }
@@ -1,38 +1,112 @@
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use bytes::Bytes;
-use log::warn;
+use fs2::FileExt;
+use log::{error, warn};
 use parking_lot::{Mutex, RwLock};
 
 use crate::batch::{log_record_key_with_seq, NON_TRANSACTION_SEQ_NO, parse_log_record_key};
-use crate::data::data_file::{DATA_FILE_NAME_SUFFIX, DataFile};
-use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType, ReadLogRecord, TransactionRecord};
+use crate::blob::BlobStore;
+use crate::data::data_file::{DATA_FILE_NAME_SUFFIX, DataFile, get_data_file_name};
+use crate::data::log_record::{decode_log_record_pos, LogRecord, LogRecordPos, LogRecordType, ReadLogRecord, TransactionRecord};
 use crate::errors::{Errors, Result};
 use crate::index::{Indexer, new_indexer};
-use crate::options::Options;
+use crate::merge::{get_non_merge_file_id, load_merge_files, MergeContext, MergeWorker};
+use crate::merge_operator::{MAX_OPERAND_CHAIN_LEN, OperandLinks, resolve_operands};
+use crate::options::{IteratorOptions, Options, RecoveryMode};
+use crate::snapshot::History;
+use crate::ttl::{Expirations, now_millis};
+use crate::util::file::dir_disk_size;
 
 const INITIAL_FILE_ID: u32 = 0;
+//数据目录下的文件锁,保证同一时刻只有一个Engine实例使用这个目录
+const FILE_LOCK_NAME: &str = "flock";
+//被丢弃的损坏数据所在文件的后缀
+const CORRUPT_FILE_NAME_SUFFIX: &str = ".corrupt";
 
 //使用一个叫做bytes的crate
 //bitcask存储引擎实例结构
 pub struct Engine {
-	options: Arc<Options>,
-	active_file: Arc<RwLock<DataFile>>,
+	pub(crate) options: Arc<Options>,
+	pub(crate) active_file: Arc<RwLock<DataFile>>,
 	//当前活跃文件
-	older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
+	pub(crate) older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
 	//旧的数据文件
-	pub(crate) indexer: Box<dyn Indexer>,
+	pub(crate) indexer: Arc<dyn Indexer>,
 	//索引接口的实现
 	file_ids: Vec<u32>,
 	//数据库启动时的文件id,只用于加载索引时使用,不能在其他地方更新或使用
-	pub(crate) batch_commit_lock: Mutex<()>,
-	//事务提交保证串行化的锁
+	pub(crate) batch_commit_lock: Arc<RwLock<()>>,
+	//事务提交保证串行化的锁,批量提交、快照和merge等持有写锁
+	//单条写入只持有读锁,在活跃文件的锁里面完成追加和提交,互相之间可以并发地写blob文件和编码数据
 	pub(crate) seq_no: Arc<AtomicUsize>,
 	//全局事务序列号
+	pub(crate) reclaimable_sizes: Arc<RwLock<HashMap<u32, u64>>>,
+	//每个数据文件中可以被merge回收的字节数
+	pub(crate) merge_context: MergeContext,
+	//merge用到的状态,和上面的字段共享
+	merge_worker: Mutex<Option<MergeWorker>>,
+	//后台merge线程
+	lock_file: File,
+	//持有数据目录的文件锁,文件关闭时锁会自动释放
+	closed: AtomicBool,
+	//调用close之后不再允许写入,文件锁已经释放了
+	recovery_report: RecoveryReport,
+	//打开数据库时丢弃的损坏数据
+	pub(crate) history: History,
+	//快照存在期间被修改的数据的旧版本
+	pub(crate) operand_links: Arc<OperandLinks>,
+	//merge_value写入的操作数之间的链表
+	pub(crate) expirations: Arc<Expirations>,
+	//设置了过期时间的数据在索引中的位置
+	pub(crate) blob_store: Arc<BlobStore>,
+	//存放大value的blob文件
+}
+
+//数据库的统计信息
+#[derive(Debug, Clone)]
+pub struct Stat {
+	//key的数量,不包括已经过期的key
+	pub key_num: usize,
+	//数据文件的数量
+	pub data_file_num: usize,
+	//可以被merge回收的数据量(字节)
+	pub reclaimable_size: u64,
+	//数据目录所占磁盘空间的大小(字节)
+	pub disk_size: u64,
+	//每个数据文件的统计信息,按照文件id升序排列
+	pub data_files: Vec<DataFileStat>,
+}
+
+//单个数据文件的统计信息
+#[derive(Debug, Clone)]
+pub struct DataFileStat {
+	pub file_id: u32,
+	//数据文件的大小(字节)
+	pub total_size: u64,
+	//其中可以被merge回收的数据量(字节)
+	pub reclaimable_size: u64,
+}
+
+//打开数据库时因为数据损坏而丢失的数据
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+	pub lost_ranges: Vec<LostRange>,
+	//StopAtFirstCorruption模式下丢弃的数据被移动到了这些文件中
+	pub quarantined_files: Vec<PathBuf>,
+}
+
+//数据文件中[start, end)范围内的数据没有被加载
+#[derive(Debug, Clone, PartialEq)]
+pub struct LostRange {
+	pub file_id: u32,
+	pub start: u64,
+	pub end: u64,
 }
 
 //别的crate里面也有为Engine实现的方法
@@ -52,11 +126,30 @@ impl Engine {
 				return Err(Errors::FailedToCreateDatabaseDir);
 			}
 		}
+		//加上文件锁,防止多个进程(或者同一进程中多个Engine)同时写同一个数据目录
+		let lock_file = match OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(dir_path.join(FILE_LOCK_NAME))
+		{
+			Ok(file) => file,
+			Err(e) => {
+				error!("failed to open lock file: {}", e);
+				return Err(Errors::FailedToOpenDataFile);
+			}
+		};
+		if lock_file.try_lock_exclusive().is_err() {
+			return Err(Errors::DatabaseIsUsing);
+		}
+		//上一次merge完成后的数据文件还在merge目录里面,先用它们替换掉旧的数据文件
+		load_merge_files(dir_path)?;
 		//加载数据文件,把目录里面的文件加载为DataFile结构,按照id逆序存入一个Vec中
-		let mut data_files = load_data_files(dir_path)?;
-		//设置file_id信息
+		let mut data_files = load_data_files(dir_path, &opts)?;
+		//设置file_id信息,加载索引时要按照id从小到大的顺序,新的数据才能覆盖旧的数据
 		let mut file_ids = vec![];
-		for data_file in &data_files {
+		for data_file in data_files.iter().rev() {
 			file_ids.push(data_file.get_file_id());
 		}
 		//id最大的元素为active file,其他文件放入older_files HashMap中即可
@@ -71,25 +164,65 @@ impl Engine {
 		//如果目录里面无文件,需要创建一个数据文件,作为active file
 		let active_file = match data_files.pop() {
 			Some(file) => file,
-			None => DataFile::new(dir_path, INITIAL_FILE_ID)?, //这代表数据库目录里面没有一个文件
+			//这代表数据库目录里面没有一个文件
+			None => DataFile::new(dir_path, INITIAL_FILE_ID)?.with_key_provider(opts.key_provider.clone()),
 		};
 		//构造存储引擎实例
-		let engine = Engine {
-			options: Arc::new(opts.clone()),
-			active_file: Arc::new(RwLock::new(active_file)),
-			older_files: Arc::new(RwLock::new(older_files)),
+		let options = Arc::new(opts.clone());
+		let active_file = Arc::new(RwLock::new(active_file));
+		let older_files = Arc::new(RwLock::new(older_files));
+		let indexer = new_indexer(opts.index_type);
+		let batch_commit_lock = Arc::new(RwLock::new(()));
+		let reclaimable_sizes = Arc::new(RwLock::new(HashMap::new()));
+		let operand_links = Arc::new(OperandLinks::default());
+		let expirations = Arc::new(Expirations::default());
+		let blob_store = Arc::new(BlobStore::open(&opts)?);
+		let merge_context = MergeContext::new(
+			options.clone(),
+			active_file.clone(),
+			older_files.clone(),
+			indexer.clone(),
+			batch_commit_lock.clone(),
+			reclaimable_sizes.clone(),
+			operand_links.clone(),
+			blob_store.clone(),
+		);
+		let mut engine = Engine {
+			options,
+			active_file,
+			older_files,
 			file_ids,
-			indexer: new_indexer(opts.index_type),
-			batch_commit_lock: Mutex::new(()),
+			indexer,
+			batch_commit_lock,
 			seq_no: Arc::new(AtomicUsize::new(0)),
+			reclaimable_sizes,
+			merge_context,
+			merge_worker: Mutex::new(None),
+			lock_file,
+			closed: AtomicBool::new(false),
+			recovery_report: RecoveryReport::default(),
+			history: History::default(),
+			operand_links,
+			expirations,
+			blob_store,
 		};
+		// 先从hint文件中加载merge过的数据的索引
+		engine.load_index_from_hint_file()?;
 		// 从数据文件中加载索引
-		let current_seq_no = engine.load_index_from_data_files()?;
+		let (current_seq_no, recovery_report) = engine.load_index_from_data_files()?;
+		engine.recovery_report = recovery_report;
+		//索引中引用的value之外都是blob文件中的失效数据
+		engine.blob_store.reset_garbage_sizes();
+		engine.collapse_long_operand_chains();
 
 		// 更新当前事务序列号
 		if current_seq_no > 0 {
 			engine.seq_no.store(current_seq_no, Ordering::SeqCst);
 		}
+		//索引加载完成后再开启后台merge
+		if engine.options.auto_merge {
+			*engine.merge_worker.lock() = Some(MergeWorker::start(engine.merge_context.clone()));
+		}
 		Ok(engine)
 	}
 	//数据写入
@@ -98,31 +231,77 @@ impl Engine {
 		if key.is_empty() {
 			return Err(Errors::KeyIsEmpty);
 		}
+		//和批量提交互斥,每次写入都分配一个新的序列号,快照根据序列号判断数据是否可见
+		let _lock = self.batch_commit_lock.read();
+		self.put_with_lock(&key, &value, 0)
+	}
+	//调用方需要持有batch_commit_lock的读锁或者写锁,expire_at为0表示永不过期
+	pub(crate) fn put_with_lock(&self, key: &[u8], value: &[u8], expire_at: u64) -> Result<()> {
+		self.write_value(key, value, expire_at, |pos| {
+			let seq_no = self.seq_no.fetch_add(1, Ordering::SeqCst) + 1;
+			self.commit_index(key.to_vec(), Some(pos), seq_no);
+		})?;
+		Ok(())
+	}
+	//把key的值以非事务的形式写入数据文件,返回写入的位置,调用方需要持有batch_commit_lock
+	//commit在活跃文件的锁里面执行,见append_log_record_then
+	fn write_value(&self, key: &[u8], value: &[u8], expire_at: u64, commit: impl FnOnce(LogRecordPos)) -> Result<LogRecordPos> {
 		//构造LogRecord
 		let mut record = LogRecord {
 			//直接调用put的是非事务的LogRecord,为了统一,用NO_TRANSACTION_SEQ_NO标识其key
 			key: log_record_key_with_seq(key.to_vec(), NON_TRANSACTION_SEQ_NO),
 			value: value.to_vec(),
 			rec_type: LogRecordType::NORMAL,
+			expire_at,
 		};
+		let blob_pos = self.separate_blob_value(key, &mut record)?;
 		//将数据追加写入到当前的活跃文件中
-		let log_record_pos = self.append_log_record(&mut record)?;
-		//更新内存索引
-		if !self.indexer.put(key.to_vec(), log_record_pos) {
-			return Err(Errors::IndexUpdateFailed);
+		self.append_log_record_then(&mut record, |pos| {
+			self.expirations.set(pos, expire_at);
+			if let Some(blob_pos) = blob_pos {
+				self.blob_store.set_pointer(pos, blob_pos);
+			}
+			commit(pos);
+		})
+	}
+	//大value先写入blob文件,record中只保存其位置,返回value在blob文件中的位置
+	pub(crate) fn separate_blob_value(&self, key: &[u8], record: &mut LogRecord) -> Result<Option<LogRecordPos>> {
+		match self.options.blob_value_threshold {
+			Some(threshold) if record.rec_type == LogRecordType::NORMAL && record.value.len() >= threshold => {
+				self.check_closed()?;
+				let blob_pos = self.blob_store.write(key, &record.value)?;
+				record.value = blob_pos.encode();
+				record.rec_type = LogRecordType::BLOB_POINTER;
+				Ok(Some(blob_pos))
+			}
+			_ => Ok(None),
 		}
+	}
+	//把key在old_pos处的值原样重写到新的位置,调用方需要持有batch_commit_lock并确认内存索引仍然指向old_pos
+	//读到的值没有变化,所以不分配序列号也不保存旧版本,快照和事务的冲突检测都感知不到这次重写
+	pub(crate) fn rewrite_with_lock(&self, key: &[u8], old_pos: LogRecordPos, value: &[u8], expire_at: u64) -> Result<()> {
+		self.write_value(key, value, expire_at, |pos| {
+			self.indexer.put(key.to_vec(), pos);
+			self.retire_pos(old_pos, Some(pos));
+		})?;
 		Ok(())
 	}
 	//追加数据到当前活跃文件中,返回写入的file_id和offset(用结构体LogRecordPos封装),用于更新内存里面的索引
 	//注意当前active file容量达到最大后要把其加入old_files哈希表里,创建新的active file
 	//这个方法在当前crate(lib.rs)的别的模块里面也会使用,令其可见性为pub(crate)
 	pub(crate) fn append_log_record(&self, log_record: &mut LogRecord) -> Result<LogRecordPos> {
+		self.append_log_record_then(log_record, |_| {})
+	}
+	//追加数据之后,在释放活跃文件的锁之前执行commit
+	//只持有batch_commit_lock读锁的单条写入在这里更新内存索引,提交的顺序和数据文件中的顺序一致,重启之后加载的结果相同
+	pub(crate) fn append_log_record_then(&self, log_record: &mut LogRecord, commit: impl FnOnce(LogRecordPos)) -> Result<LogRecordPos> {
 		let dir_path = self.options.dir_path.clone();
 		//对输入的数据进行编码
-		let enc_record = log_record.encode();
+		let enc_record = log_record.encode_with(self.options.compression, self.options.key_provider.as_deref())?;
 		let record_len = enc_record.len() as u64;
 		//获取到当前活跃文件的写锁
 		let mut active_file = self.active_file.write();
+		self.check_closed()?;
 		//判断当前活跃文件是否到达写入的阈值
 		if active_file.get_write_off() + record_len > self.options.data_file_size {
 			//将当前的活跃文件进行持久化
@@ -130,10 +309,10 @@ impl Engine {
 			let current_fid = active_file.get_file_id();
 			//将旧的数据文件放入map中
 			let mut older_files = self.older_files.write();
-			let old_file = DataFile::new(&dir_path, current_fid)?;
+			let old_file = DataFile::new(&dir_path, current_fid)?.with_key_provider(self.options.key_provider.clone());
 			older_files.insert(current_fid, old_file);
 			//打开新的数据文件,作为新的active file,同时其file_id为前一个active file的id + 1
-			let new_file = DataFile::new(&dir_path, current_fid + 1)?;
+			let new_file = DataFile::new(&dir_path, current_fid + 1)?.with_key_provider(self.options.key_provider.clone());
 			*active_file = new_file;
 		}
 		let write_off = active_file.get_write_off();
@@ -143,32 +322,136 @@ impl Engine {
 		if self.options.sync_writes {
 			active_file.sync()?;
 		}
-		Ok(LogRecordPos {
+		let pos = LogRecordPos {
 			file_id: active_file.get_file_id(),
 			offset: write_off,
-		})
+			size: record_len as u32,
+		};
+		commit(pos);
+		Ok(pos)
+	}
+	//记录一条已经失效的数据,其占用的空间可以在merge时回收
+	pub(crate) fn add_reclaimable(&self, pos: LogRecordPos) {
+		let mut reclaimable_sizes = self.reclaimable_sizes.write();
+		*reclaimable_sizes.entry(pos.file_id).or_insert(0) += pos.size as u64;
+	}
+	//提交写入后更新内存索引,pos为None表示删除
+	//调用方需要持有batch_commit_lock的写锁,或者持有读锁并且在append_log_record_then的commit里面调用
+	//有活跃的快照时先保存旧版本
+	pub(crate) fn commit_index(&self, key: Vec<u8>, pos: Option<LogRecordPos>, seq_no: usize) {
+		self.history.record(self.indexer.as_ref(), &key, seq_no);
+		let old_pos = match pos {
+			Some(pos) => self.indexer.put(key, pos),
+			None => self.indexer.delete(key),
+		};
+		if let Some(old_pos) = old_pos {
+			self.retire_pos(old_pos, pos);
+		}
+	}
+	//内存索引中old_pos被pos替换之后调用,被覆盖的旧数据可以在merge时回收
+	fn retire_pos(&self, old_pos: LogRecordPos, pos: Option<LogRecordPos>) {
+		self.add_reclaimable(old_pos);
+		self.expirations.remove(old_pos);
+		//操作数之前的数据还会被读到,其他情况下被覆盖的value成为blob文件中的失效数据
+		if pos.is_none_or(|pos| self.operand_links.prev(pos).is_none()) {
+			self.release_blob_values(old_pos);
+			//被覆盖的操作数链表只有快照还会读取,没有快照时直接删除
+			match self.history.has_snapshots() {
+				true => self.operand_links.retire(old_pos),
+				false => self.operand_links.unlink(old_pos),
+			}
+		}
 	}
 	//通过LogRecordPos来找到对应的value,以Vec<u8>形式返回
+	//读取之前value所在的blob文件可能已经被gc删除了,这时value已经被重写到了新的位置,改为读取key最新的数据
 	pub(crate) fn get_value_by_position(&self, pos: LogRecordPos) -> Result<Bytes> {
-		let active_file = self.active_file.read();
-		let older_file = self.older_files.read();
-		//从对应的文件里面读出LogRecord
-		let log_record = if active_file.get_file_id() == pos.file_id {
-			//记录在当前活跃文件里
-			active_file.read_log_record(pos.offset)?.record
-		} else {
-			let file = older_file.get(&pos.file_id);
-			if file.is_none() {
-				//找不到对应的数据文件
-				return Err(Errors::DataFileNotFound);
+		match self.read_value(pos, now_millis(), true) {
+			Err(Errors::DataFileNotFound) => {
+				let log_record = read_log_record_at(&self.active_file, &self.older_files, pos)?;
+				let (key, _) = parse_log_record_key(&log_record.key)?;
+				match self.indexer.get(key) {
+					Some(new_pos) if new_pos.file_id != pos.file_id || new_pos.offset != pos.offset => {
+						self.get_value_by_position(new_pos)
+					}
+					_ => Err(Errors::DataFileNotFound),
+				}
 			}
-			file.unwrap().read_log_record(pos.offset)?.record
-		};
-		//判断LogRecord的类型
-		if log_record.rec_type == LogRecordType::DELETED {
+			value => value,
+		}
+	}
+	//按now判断数据是否过期,快照用创建时的时间读取
+	//读到的不一定是最新的值,不能用来合并操作数链表
+	pub(crate) fn get_value_at(&self, pos: LogRecordPos, now: u64) -> Result<Bytes> {
+		self.read_value(pos, now, false)
+	}
+	fn read_value(&self, pos: LogRecordPos, now: u64, collapse: bool) -> Result<Bytes> {
+		//value在blob文件中时读出实际的value
+		let read = |pos| self.blob_store.materialize(read_log_record_at(&self.active_file, &self.older_files, pos)?, now);
+		let log_record = read_log_record_at(&self.active_file, &self.older_files, pos)?;
+		//过期的数据和被删除的数据一样
+		if log_record.is_expired(now) {
 			return Err(Errors::KeyNotFound);
 		}
-		Ok(log_record.value.into()) //Bytes结构体有实现From<Vec<u8>>的trait
+		let log_record = self.blob_store.materialize(log_record, now)?;
+		//判断LogRecord的类型
+		match log_record.rec_type {
+			LogRecordType::DELETED => Err(Errors::KeyNotFound),
+			LogRecordType::MERGE_OPERAND => {
+				let (key, _) = parse_log_record_key(&log_record.key)?;
+				let operator = self.options.merge_operator.as_deref();
+				match resolve_operands(operator, &self.operand_links, pos, log_record, now, read)? {
+					Some((value, base_expire_at)) => {
+						//基础值过期之后合并的结果会变化,不能合并成一条永不过期的数据
+						if collapse && base_expire_at == 0 && self.operand_links.len(pos) > MAX_OPERAND_CHAIN_LEN {
+							self.collapse_operands(&key, pos, &value);
+						}
+						Ok(value.into())
+					}
+					//读取过程中key被覆盖,旧的链表已经删除了,改为读取key最新的数据
+					None => match self.indexer.get(key) {
+						Some(new_pos) if new_pos.file_id != pos.file_id || new_pos.offset != pos.offset => {
+							self.read_value(new_pos, now, collapse)
+						}
+						_ => Err(Errors::KeyNotFound),
+					},
+				}
+			}
+			_ => Ok(log_record.value.into()), //Bytes结构体有实现From<Vec<u8>>的trait
+		}
+	}
+	//操作数链表太长时每次读取都要读很多条记录,把合并之后的值重写成一条普通的数据
+	//重写失败不影响这次读取,下次读取时会再尝试
+	fn collapse_operands(&self, key: &[u8], pos: LogRecordPos, value: &[u8]) {
+		//读取可能发生在持有batch_commit_lock的写入里面,比如compare_and_swap,这时跳过
+		let Some(_lock) = self.batch_commit_lock.try_write() else {
+			return;
+		};
+		//读取之后key可能已经被修改了
+		if self.indexer.get(key.to_vec()).is_none_or(|index_pos| index_pos.file_id != pos.file_id || index_pos.offset != pos.offset) {
+			return;
+		}
+		if let Err(e) = self.rewrite_with_lock(key, pos, value, 0) {
+			warn!("failed to collapse merge operands: {}", e);
+		}
+	}
+	//打开数据库时把过长的操作数链表合并掉,之后的读取不用再读整条链表
+	fn collapse_long_operand_chains(&self) {
+		if self.options.merge_operator.is_none() || self.operand_links.is_empty() {
+			return;
+		}
+		let mut index_iter = self.indexer.iterator(&IteratorOptions::default());
+		let mut long_chains = vec![];
+		while let Some((_, pos)) = index_iter.next() {
+			if self.operand_links.len(*pos) > MAX_OPERAND_CHAIN_LEN {
+				long_chains.push(*pos);
+			}
+		}
+		//读取时会合并
+		for pos in long_chains {
+			if let Err(e) = self.get_value_by_position(pos) {
+				warn!("failed to resolve merge operands: {}", e);
+			}
+		}
 	}
 	//数据读取
 	pub fn get(&self, key: Bytes) -> Result<Bytes> {
@@ -191,92 +474,256 @@ impl Engine {
 		if key.is_empty() {
 			return Err(Errors::KeyIsEmpty);
 		}
+		let _lock = self.batch_commit_lock.read();
+		self.delete_with_lock(&key)
+	}
+	//调用方需要持有batch_commit_lock的读锁或者写锁
+	fn delete_with_lock(&self, key: &[u8]) -> Result<()> {
 		//从索引中取出相应的数据,如果不存在则直接返回
 		let pos = self.indexer.get(key.to_vec());
 		if pos.is_none() {
 			return Ok(());
 		}
+		//墓碑值和put一样要带上序列号,否则加载时无法解析出真实的key
 		let mut record = LogRecord {
-			key: key.to_vec(),
+			key: log_record_key_with_seq(key.to_vec(), NON_TRANSACTION_SEQ_NO),
 			value: Default::default(),
 			rec_type: LogRecordType::DELETED,
+			expire_at: 0,
 		};
-		self.append_log_record(&mut record)?;
-		//从内存索引中删除key
-		let ok = self.indexer.delete(key.to_vec());
-		if !ok {
-			return Err(Errors::IndexUpdateFailed);
+		self.append_log_record_then(&mut record, |pos| {
+			//墓碑值本身也是可以回收的
+			self.add_reclaimable(pos);
+			let seq_no = self.seq_no.fetch_add(1, Ordering::SeqCst) + 1;
+			//从内存索引中删除key
+			self.commit_index(key.to_vec(), None, seq_no);
+		})?;
+		Ok(())
+	}
+	//追加一个操作数,读取时用Options::merge_operator把操作数合并到之前的值上面
+	//不需要先读出旧值,并发的merge_value不会互相覆盖
+	pub fn merge_value(&self, key: Bytes, operand: Bytes) -> Result<()> {
+		if key.is_empty() {
+			return Err(Errors::KeyIsEmpty);
 		}
+		if self.options.merge_operator.is_none() {
+			return Err(Errors::MergeOperatorNotFound);
+		}
+		let mut record = LogRecord {
+			key: log_record_key_with_seq(key.to_vec(), NON_TRANSACTION_SEQ_NO),
+			value: operand.to_vec(),
+			rec_type: LogRecordType::MERGE_OPERAND,
+			expire_at: 0,
+		};
+		let _lock = self.batch_commit_lock.read();
+		self.append_log_record_then(&mut record, |pos| {
+			let seq_no = self.seq_no.fetch_add(1, Ordering::SeqCst) + 1;
+			//操作数指向key之前的数据,被覆盖的位置在读取时仍然需要
+			self.operand_links.link(pos, self.indexer.get(key.to_vec()));
+			self.commit_index(key.to_vec(), Some(pos), seq_no);
+		})?;
 		Ok(())
 	}
+	//当前值等于expected时写入new,None表示key不存在或者删除key
+	//比较和写入在batch_commit_lock里面完成,和其他写入以及批量提交互斥,返回是否写入成功
+	pub fn compare_and_swap(&self, key: Bytes, expected: Option<Bytes>, new: Option<Bytes>) -> Result<bool> {
+		if key.is_empty() {
+			return Err(Errors::KeyIsEmpty);
+		}
+		let _lock = self.batch_commit_lock.write();
+		let current = match self.get(key.clone()) {
+			Ok(value) => Some(value),
+			Err(Errors::KeyNotFound) => None,
+			Err(e) => return Err(e),
+		};
+		if current != expected {
+			return Ok(false);
+		}
+		match new {
+			Some(value) => self.put_with_lock(&key, &value, 0)?,
+			None => self.delete_with_lock(&key)?,
+		}
+		Ok(true)
+	}
+	//key不存在时才写入,返回是否写入成功
+	pub fn put_if_absent(&self, key: Bytes, value: Bytes) -> Result<bool> {
+		self.compare_and_swap(key, None, Some(value))
+	}
+	//当前值等于expected时才删除,返回是否删除成功
+	pub fn delete_if_equals(&self, key: Bytes, expected: Bytes) -> Result<bool> {
+		self.compare_and_swap(key, Some(expected), None)
+	}
 	pub fn sync(&self) -> Result<()> {
-		//只用sync 活跃文件就好了
+		//只用sync 活跃文件就好了,blob文件中的value要先于引用它的记录持久化
+		self.blob_store.sync()?;
 		self.active_file.write().sync()
 	}
 	pub fn close(&self) -> Result<()> {
-		self.sync()
+		//先停止后台merge线程
+		if let Some(worker) = self.merge_worker.lock().take() {
+			worker.stop();
+		}
+		//在活跃文件的写锁里面设置,正在进行的追加写入完成之后,后面的写入都会失败
+		{
+			let _active_file = self.active_file.write();
+			self.closed.store(true, Ordering::SeqCst);
+		}
+		self.sync()?;
+		//释放文件锁,之后这个目录可以被其他实例打开
+		if let Err(e) = FileExt::unlock(&self.lock_file) {
+			error!("failed to unlock database directory: {}", e);
+		}
+		Ok(())
+	}
+	//数据库关闭之后返回错误
+	pub(crate) fn check_closed(&self) -> Result<()> {
+		if self.closed.load(Ordering::SeqCst) {
+			return Err(Errors::DatabaseClosed);
+		}
+		Ok(())
+	}
+	//打开数据库时被跳过或者截断的数据,取决于Options::recovery_mode
+	pub fn recovery_report(&self) -> &RecoveryReport {
+		&self.recovery_report
+	}
+	//获取数据库的统计信息
+	pub fn stat(&self) -> Result<Stat> {
+		let mut data_files = vec![];
+		{
+			let active_file = self.active_file.read();
+			let older_files = self.older_files.read();
+			data_files.push((active_file.get_file_id(), active_file.file_size()));
+			for (file_id, data_file) in older_files.iter() {
+				data_files.push((*file_id, data_file.file_size()));
+			}
+		}
+		data_files.sort_unstable();
+		let reclaimable_sizes = self.reclaimable_sizes.read();
+		let data_files: Vec<DataFileStat> = data_files
+			.into_iter()
+			.map(|(file_id, total_size)| DataFileStat {
+				file_id,
+				total_size,
+				reclaimable_size: reclaimable_sizes.get(&file_id).copied().unwrap_or(0),
+			})
+			.collect();
+		Ok(Stat {
+			key_num: self.indexer.len().saturating_sub(self.expirations.count_expired(now_millis())),
+			data_file_num: data_files.len(),
+			reclaimable_size: data_files.iter().map(|f| f.reclaimable_size).sum(),
+			disk_size: dir_disk_size(&self.options.dir_path),
+			data_files,
+		})
 	}
 
 	//遍历数据文件中的内容,并依次处理其中所有的记录,构建其内存索引key->LogRecordPos
-	//这一步比较耗时,后面可以优化(空间换时间,用一个hint文件来存储相关信息)
-	fn load_index_from_data_files(&self) -> Result<usize> {
+	//merge过的数据文件已经从hint文件中加载了索引,这里只处理比它们新的数据文件
+	fn load_index_from_data_files(&self) -> Result<(usize, RecoveryReport)> {
+		let mut report = RecoveryReport::default();
 		if self.file_ids.is_empty() {
-			return Ok(NON_TRANSACTION_SEQ_NO);
+			return Ok((NON_TRANSACTION_SEQ_NO, report));
 		}
 		//用来记录用到哪个seq_no了
 		let mut current_seq_no = NON_TRANSACTION_SEQ_NO;
-		let active_file = self.active_file.read();
-		let older_file = self.older_files.read();
+		//StopAtFirstCorruption模式下可能需要替换活跃文件,这里直接拿写锁
+		let mut active_file = self.active_file.write();
+		let mut older_file = self.older_files.write();
 		//暂存事务相关的数据,存储对应的LogRecord和其pos
 		let mut transaction_record = HashMap::new();
+		let non_merge_fid = get_non_merge_file_id(&self.options.dir_path)?;
+		//StopAtFirstCorruption模式下遇到损坏记录的文件id和位置
+		let mut stopped_at = None;
 		//遍历所有的文件
 		for (i, file_id) in self.file_ids.iter().enumerate() {
+			//id比non_merge_fid小的文件是merge产生的,其索引已经从hint文件中加载过了
+			if *file_id < non_merge_fid {
+				continue;
+			}
+			let is_newest_file = i == self.file_ids.len() - 1;
+			let data_file = match *file_id == active_file.get_file_id() {
+				true => &*active_file,
+				false => older_file.get(file_id).ok_or(Errors::DataFileNotFound)?,
+			};
 			let mut offset = 0;
 			loop {
-				let log_record_res = match *file_id == active_file.get_file_id() {
-					true => active_file.read_log_record(offset),
-					false => {
-						let data_file = older_file.get(file_id).unwrap();
-						data_file.read_log_record(offset)
-					}
-				};
 				//这里是为了解构处record和size两个变量,size同名所以可以不用写字段名
 				let ReadLogRecord {
 					record: mut log_record,
-					size: size,
-				} = match log_record_res {
+					size,
+				} = match data_file.read_log_record(offset) {
 					Ok(result) => result,
-					Err(e) => {
-						//读到文件尾了,直接读取下一个文件
-						if e == Errors::ReadDataFileEOF {
+					//读到文件尾了,直接读取下一个文件
+					Err(Errors::ReadDataFileEOF) => break,
+					Err(e) if is_corrupted_record_err(&e) => {
+						if !is_newest_file && self.options.recovery_mode == RecoveryMode::Strict {
+							return Err(e);
+						}
+						//最新的数据文件末尾的记录可能因为写入过程中崩溃而不完整,后面会被截断
+						//这里先看后面还有没有完整的记录,没有的话就是末尾没写完的记录
+						let next_offset = data_file.next_valid_record_offset(offset);
+						if is_newest_file && next_offset.is_none() {
 							break;
 						}
-						return Err(e);
+						match self.options.recovery_mode {
+							RecoveryMode::Strict => return Err(e),
+							RecoveryMode::SkipCorrupted => {
+								let end = next_offset.unwrap_or_else(|| data_file.file_size());
+								warn!("skip corrupted data in data file {} from {} to {}: {}", file_id, offset, end, e);
+								report.lost_ranges.push(LostRange {
+									file_id: *file_id,
+									start: offset,
+									end,
+								});
+								//跳过的数据还在文件里面,merge时可以回收
+								*self.reclaimable_sizes.write().entry(*file_id).or_insert(0) += end - offset;
+								match next_offset {
+									Some(next_offset) => {
+										offset = next_offset;
+										continue;
+									}
+									None => break,
+								}
+							}
+							RecoveryMode::StopAtFirstCorruption => {
+								warn!("stop loading at corrupted data in data file {} at {}: {}", file_id, offset, e);
+								stopped_at = Some((*file_id, offset));
+								break;
+							}
+						}
 					}
+					Err(e) => return Err(e),
 				};
 				//构建内存索引
 				let log_record_pos = LogRecordPos {
 					file_id: *file_id,
 					offset,
+					size: size as u32,
 				};
 
 
-				let (real_key, seq_no) = parse_log_record_key(&log_record.key);
+				let (real_key, seq_no) = parse_log_record_key(&log_record.key)?;
 				//非事务提交,直接更新其内存索引
 				if seq_no == NON_TRANSACTION_SEQ_NO {
-					self.update_index(real_key, log_record.rec_type, log_record_pos)?;
+					self.update_index(real_key, &log_record, log_record_pos);
 				} else {
 					//读取到TXN_FINISHED的记录说明何其seq_no相同的记录都是有效的
 					if log_record.rec_type == LogRecordType::TXN_FINISHED {
 						//需要指明类型
 						// dbg!(&transaction_record);
-						let records: &Vec<TransactionRecord> = transaction_record.get(&seq_no).unwrap();
+						//事务里的数据可能已经被merge掉了,这时没有暂存的记录
+						let records: Vec<TransactionRecord> = transaction_record.remove(&seq_no).unwrap_or_default();
 						for txn_record in records {
-							self.update_index(txn_record.record.key.clone(), txn_record.record.rec_type, txn_record.pos)?;
+							let real_key = txn_record.record.key.clone();
+							self.update_index(real_key, &txn_record.record, txn_record.pos);
 						}
-						transaction_record.remove(&seq_no);
+						//事务完成的标识只在加载索引时有用
+						self.add_reclaimable(log_record_pos);
 					} else {
+						//加载索引时用不到value,不需要保存在内存中,blob记录的value是blob文件中的位置,需要保留
 						log_record.key = real_key;
+						if log_record.rec_type != LogRecordType::BLOB_POINTER {
+							log_record.value = Vec::new();
+						}
 						transaction_record.entry(seq_no).or_insert(Vec::new())
 							.push(TransactionRecord {
 								record: log_record,
@@ -292,43 +739,201 @@ impl Engine {
 				//更新offset,下次读取的时候从新的位置开始
 				offset += size;
 			}
+			if stopped_at.is_some() {
+				break;
+			}
 			//设置活跃文件的offset
-			if i == self.file_ids.len() - 1 {
-				active_file.set_write_off(offset);
+			if is_newest_file {
+				truncate_tail(&active_file, offset, &mut report)?;
+			}
+		}
+		//在损坏的位置停止加载,之后的数据全部丢弃,否则下次打开时新写入的数据会在损坏的位置之后而被丢掉
+		if let Some((stop_file_id, stop_offset)) = stopped_at {
+			//损坏的文件成为新的活跃文件
+			if let Some(data_file) = older_file.remove(&stop_file_id) {
+				let newest_file = std::mem::replace(&mut *active_file, data_file);
+				older_file.insert(newest_file.get_file_id(), newest_file);
+			}
+			let dir_path = &self.options.dir_path;
+			for file_id in self.file_ids.iter().filter(|fid| **fid > stop_file_id) {
+				let data_file = older_file.remove(file_id).unwrap();
+				let corrupt_file_name = get_corrupt_file_name(dir_path, *file_id);
+				warn!("move data file {} after corrupted data to {}", file_id, corrupt_file_name.display());
+				report.lost_ranges.push(LostRange {
+					file_id: *file_id,
+					start: 0,
+					end: data_file.file_size(),
+				});
+				drop(data_file);
+				if let Err(e) = fs::rename(get_data_file_name(dir_path, *file_id), &corrupt_file_name) {
+					error!("failed to move data file: {}", e);
+					return Err(Errors::DataDirectoryCorrupted);
+				}
+				report.quarantined_files.push(corrupt_file_name);
+				self.reclaimable_sizes.write().remove(file_id);
+			}
+			//损坏位置之后的数据先复制出来再截断
+			if active_file.file_size() > stop_offset {
+				report.quarantined_files.push(quarantine_tail(dir_path, stop_file_id, stop_offset)?);
+			}
+			truncate_tail(&active_file, stop_offset, &mut report)?;
+		}
+		//没有TXN_FINISHED标识的事务数据是无效的
+		for records in transaction_record.values() {
+			for txn_record in records {
+				self.add_reclaimable(txn_record.pos);
 			}
 		}
-		Ok(current_seq_no)
+		Ok((current_seq_no, report))
 	}
-	//加载索引更新内存数据
-	fn update_index(&self, key: Vec<u8>, rec_type: LogRecordType, pos: LogRecordPos) -> Result<()> {
+	//加载索引更新内存数据,同时统计失效数据的大小
+	fn update_index(&self, key: Vec<u8>, record: &LogRecord, pos: LogRecordPos) {
+		let rec_type = record.rec_type;
 		//针对不同的LogRecordType操作不同
-		let ok = match rec_type {
+		let old_pos = match rec_type {
+			//加载时已经过期的数据和墓碑值一样,不会再被读到
+			LogRecordType::NORMAL | LogRecordType::BLOB_POINTER if record.is_expired(now_millis()) => {
+				self.add_reclaimable(pos);
+				self.indexer.delete(key.to_vec())
+			}
 			LogRecordType::NORMAL => {
+				self.expirations.set(pos, record.expire_at);
+				self.indexer.put(key.to_vec(), pos)
+			}
+			//blob文件中的value在加载完成之后统计是否有效
+			LogRecordType::BLOB_POINTER => {
+				if let Ok(blob_pos) = decode_log_record_pos(record.value.clone()) {
+					self.blob_store.set_pointer(pos, blob_pos);
+				}
+				self.expirations.set(pos, record.expire_at);
+				self.indexer.put(key.to_vec(), pos)
+			}
+			LogRecordType::DELETED => {
+				self.add_reclaimable(pos);
+				self.indexer.delete(key.to_vec())
+			}
+			LogRecordType::TXN_FINISHED => None,
+			//按写入的顺序重建操作数的链表
+			LogRecordType::MERGE_OPERAND => {
+				self.operand_links.link(pos, self.indexer.get(key.to_vec()));
 				self.indexer.put(key.to_vec(), pos)
 			}
-			LogRecordType::DELETED => self.indexer.delete(key.to_vec()),
-			LogRecordType::TXN_FINISHED => true,
 		};
-		if !ok {
-			return Err(Errors::IndexUpdateFailed);
+		//加载时没有快照,被覆盖的操作数链表不会再被读到
+		if let Some(old_pos) = old_pos {
+			self.retire_pos(old_pos, (rec_type == LogRecordType::MERGE_OPERAND).then_some(pos));
 		}
-		Ok(())
 	}
 }
 
-//先把所有数据文件的id加载入一个Vec，逆序排序，再根据这个Vec里面的file_id按序加载数据文件为DataFile
-fn load_data_files(dir_path: &PathBuf) -> Result<Vec<DataFile>> {
-	let dir = fs::read_dir(dir_path);
-	if dir.is_err() {
-		return Err(Errors::FailedToReadDataBaseDir);
+impl Drop for Engine {
+	fn drop(&mut self) {
+		//没有调用close直接drop时,也要停止后台merge线程
+		if let Some(worker) = self.merge_worker.get_mut().take() {
+			worker.stop();
+		}
+	}
+}
+
+//根据位置从活跃文件或者旧的数据文件中读出LogRecord
+pub(crate) fn read_log_record_at(
+	active_file: &RwLock<DataFile>,
+	older_files: &RwLock<HashMap<u32, DataFile>>,
+	pos: LogRecordPos,
+) -> Result<LogRecord> {
+	let active_file = active_file.read();
+	//记录在当前活跃文件里
+	if active_file.get_file_id() == pos.file_id {
+		return Ok(active_file.read_log_record(pos.offset)?.record);
 	}
+	match older_files.read().get(&pos.file_id) {
+		Some(file) => Ok(file.read_log_record(pos.offset)?.record),
+		//找不到对应的数据文件
+		None => Err(Errors::DataFileNotFound),
+	}
+}
+
+//截断活跃文件offset之后的数据并设置写偏移
+//offset之后的数据是没有完成的写入或者被丢弃的损坏数据,否则之后追加的数据会写在这些数据后面
+fn truncate_tail(active_file: &DataFile, offset: u64, report: &mut RecoveryReport) -> Result<()> {
+	let file_size = active_file.file_size();
+	if file_size > offset {
+		warn!(
+			"truncate {} bytes of incomplete data at the tail of data file {}",
+			file_size - offset,
+			active_file.get_file_id()
+		);
+		report.lost_ranges.push(LostRange {
+			file_id: active_file.get_file_id(),
+			start: offset,
+			end: file_size,
+		});
+		active_file.truncate(offset)?;
+	}
+	active_file.set_write_off(offset);
+	Ok(())
+}
+
+//把数据文件offset之后的数据复制到一个.corrupt文件中,返回这个文件的路径
+fn quarantine_tail(dir_path: &Path, file_id: u32, offset: u64) -> Result<PathBuf> {
+	let content = fs::read(get_data_file_name(dir_path, file_id)).map_err(|e| {
+		error!("failed to read data file: {}", e);
+		Errors::FailedToReadFromDataFile
+	})?;
+	let corrupt_file_name = get_corrupt_file_name(dir_path, file_id);
+	warn!("copy data of data file {} after {} to {}", file_id, offset, corrupt_file_name.display());
+	let write_res = File::create(&corrupt_file_name).and_then(|mut file| {
+		file.write_all(&content[offset as usize..])?;
+		file.sync_all()
+	});
+	if let Err(e) = write_res {
+		error!("failed to write corrupt file: {}", e);
+		return Err(Errors::FailedToWriteToDataFile);
+	}
+	Ok(corrupt_file_name)
+}
+
+//StopAtFirstCorruption模式下丢弃的数据保存在数据文件名加上.corrupt后缀的文件中
+//同一个数据文件之前可能已经有过被丢弃的数据,这时在后面再加上序号,不会覆盖之前的文件
+fn get_corrupt_file_name(dir_path: &Path, file_id: u32) -> PathBuf {
+	let data_file_name = get_data_file_name(dir_path, file_id);
+	let mut corrupt_file_name = PathBuf::from(format!("{}{}", data_file_name.display(), CORRUPT_FILE_NAME_SUFFIX));
+	let mut n = 0;
+	while corrupt_file_name.exists() {
+		n += 1;
+		corrupt_file_name = PathBuf::from(format!("{}{}.{}", data_file_name.display(), CORRUPT_FILE_NAME_SUFFIX, n));
+	}
+	corrupt_file_name
+}
+
+//读取记录时这些错误说明记录已经损坏
+pub(crate) fn is_corrupted_record_err(e: &Errors) -> bool {
+	matches!(
+		e,
+		Errors::InvalidLogRecordCrc
+			| Errors::IncompleteLogRecord
+			| Errors::InvalidLogRecordHeader
+			| Errors::UnknownLogRecordType
+	)
+}
+
+//先把所有数据文件的id加载入一个Vec，逆序排序，再根据这个Vec里面的file_id按序加载数据文件为DataFile
+fn load_data_files(dir_path: &PathBuf, opts: &Options) -> Result<Vec<DataFile>> {
+	let dir = match fs::read_dir(dir_path) {
+		Ok(dir) => dir,
+		Err(_) => return Err(Errors::FailedToReadDataBaseDir),
+	};
 	let mut file_ids = vec![];
 	let mut data_files = vec![];
-	for file in dir.unwrap() {
+	for file in dir {
 		//拿到文件名
-		let entry = file.unwrap();
+		let entry = file.map_err(|_| Errors::FailedToReadDataBaseDir)?;
 		let file_os_str = entry.file_name();
-		let file_name = file_os_str.to_str().unwrap();
+		//不是utf8编码的文件名肯定不是我们的数据文件
+		let file_name = match file_os_str.to_str() {
+			Some(file_name) => file_name,
+			None => continue,
+		};
 		//判断文件是不是我们对应的数据文件(以.data为后缀)
 		if file_name.ends_with(DATA_FILE_NAME_SUFFIX) {
 			//文件名的格式为数字+.data
@@ -344,7 +949,7 @@ fn load_data_files(dir_path: &PathBuf) -> Result<Vec<DataFile>> {
 	file_ids.sort_unstable_by(|a, b| b.cmp(a));
 	//遍历所有的文件id,依次打开对应的数据文件(因为这是日志型数据库)
 	for file_id in file_ids {
-		data_files.push(DataFile::new(dir_path, file_id)?);
+		data_files.push(DataFile::new(dir_path, file_id)?.with_key_provider(opts.key_provider.clone()));
 	}
 	Ok(data_files)
 }
@@ -358,5 +963,14 @@ fn check_options(opts: &Options) -> Option<Errors> {
 	if opts.data_file_size == 0 {
 		return Some(Errors::DataFileSizeTooSmall);
 	}
+	if opts.merge_ratio_threshold <= 0.0 || opts.merge_ratio_threshold > 1.0 {
+		return Some(Errors::InvalidMergeRatio);
+	}
+	if opts.merge_check_interval.is_zero() {
+		return Some(Errors::InvalidMergeCheckInterval);
+	}
+	if opts.blob_gc_ratio <= 0.0 || opts.blob_gc_ratio > 1.0 {
+		return Some(Errors::InvalidBlobGcRatio);
+	}
 	None
 }
@@ -1,9 +1,16 @@
+use crate::batch::{log_record_key_with_seq, NON_TRANSACTION_SEQ_NO};
+use crate::data::data_file::get_data_file_name;
+use crate::data::log_record::{LogRecord, LogRecordType};
 use crate::db::Engine;
+use crate::encryption::KeyRing;
 use crate::errors::Errors;
-use crate::options::{IndexType, Options};
+use crate::options::{Compression, IndexType, Options, RecoveryMode, WriteBatchOptions};
 use crate::util::rand_kv::{get_test_key, get_test_value};
 use bytes::Bytes;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Arc;
 #[test]
 fn test_engine_put_and_get() {
     let opts = Options {
@@ -11,6 +18,7 @@ fn test_engine_put_and_get() {
         data_file_size: 64 * 1024 * 1024,
         sync_writes: false,
         index_type: IndexType::BTree,
+        ..Default::default()
     };
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
@@ -52,13 +60,523 @@ fn test_engine_put_and_get() {
     std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
 }
 
+#[test]
+fn test_engine_with_skiplist_index() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-skiplist"),
+        data_file_size: 64 * 1024,
+        sync_writes: false,
+        index_type: IndexType::SkipList,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert!(engine.put(get_test_key(1), Bytes::from("a new value")).is_ok());
+    assert!(engine.delete(get_test_key(2)).is_ok());
+    assert_eq!(Err(Errors::KeyNotFound), engine.get(get_test_key(2)));
+    assert_eq!(999, engine.list_keys().len());
+
+    //重启之后从数据文件重建跳表索引
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(Bytes::from("a new value"), engine.get(get_test_key(1)).unwrap());
+    assert_eq!(get_test_value(999), engine.get(get_test_key(999)).unwrap());
+    assert_eq!(Err(Errors::KeyNotFound), engine.get(get_test_key(2)));
+    assert_eq!(999, engine.list_keys().len());
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
 #[test]
 fn test_engine_delete() {
     let opts = Options {
-        dir_path: PathBuf::from("/tmp/bitcask-rs-put"),
+        dir_path: PathBuf::from("/tmp/bitcask-rs-delete"),
         data_file_size: 64 * 1024 * 2014,
         sync_writes: false,
         index_type: IndexType::BTree,
+        ..Default::default()
     };
     let engine = Engine::open(opts.clone());
+    std::mem::drop(engine);
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_write_batch_restart() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-batch-restart"),
+        data_file_size: 64 * 1024 * 1024,
+        sync_writes: false,
+        index_type: IndexType::BTree,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..10 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert!(engine.delete(get_test_key(5)).is_ok());
+
+    let wb = engine.new_write_batch(WriteBatchOptions::default());
+    assert!(wb.put(get_test_key(1), Bytes::from("batch value")).is_ok());
+    assert!(wb.put(get_test_key(100), get_test_value(100)).is_ok());
+    assert!(wb.delete(get_test_key(2)).is_ok());
+    assert!(wb.commit().is_ok());
+    assert_eq!(Bytes::from("batch value"), engine.get(get_test_key(1)).unwrap());
+    assert_eq!(get_test_value(100), engine.get(get_test_key(100)).unwrap());
+    assert_eq!(Err(Errors::KeyNotFound), engine.get(get_test_key(2)));
+
+    //批量写入的值和删除在重启后保持不变
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(Bytes::from("batch value"), engine.get(get_test_key(1)).unwrap());
+    assert_eq!(get_test_value(100), engine.get(get_test_key(100)).unwrap());
+    assert_eq!(Err(Errors::KeyNotFound), engine.get(get_test_key(2)));
+    //不在batch中的写入和删除在重启后同样保持不变
+    assert_eq!(get_test_value(3), engine.get(get_test_key(3)).unwrap());
+    assert_eq!(Err(Errors::KeyNotFound), engine.get(get_test_key(5)));
+    assert_eq!(9, engine.list_keys().len());
+
+    //重启之后可以继续写入
+    assert!(engine.put(get_test_key(3), Bytes::from("after restart")).is_ok());
+    assert_eq!(Bytes::from("after restart"), engine.get(get_test_key(3)).unwrap());
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_file_lock() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-flock"),
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    //同一个目录不能被打开两次
+    let res = Engine::open(opts.clone());
+    assert_eq!(Errors::DatabaseIsUsing, res.err().unwrap());
+
+    //close之后可以重新打开
+    engine.put(get_test_key(1), get_test_value(1)).expect("failed to put");
+    engine.close().expect("failed to close");
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    //close之后旧的实例不能再写入
+    assert_eq!(Errors::DatabaseClosed, engine.put(get_test_key(2), get_test_value(2)).err().unwrap());
+    assert_eq!(Errors::DatabaseClosed, engine.delete(get_test_key(1)).err().unwrap());
+    let wb = engine.new_write_batch(WriteBatchOptions::default());
+    wb.put(get_test_key(3), get_test_value(3)).expect("failed to put");
+    assert_eq!(Errors::DatabaseClosed, wb.commit().err().unwrap());
+    assert_eq!(Errors::DatabaseClosed, engine.merge().err().unwrap());
+    assert_eq!(get_test_value(1), engine2.get(get_test_key(1)).unwrap());
+    assert_eq!(Errors::KeyNotFound, engine2.get(get_test_key(2)).err().unwrap());
+    //drop之后也可以重新打开
+    std::mem::drop(engine2);
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    engine3.close().expect("failed to close");
+    std::mem::drop(engine);
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_stat() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-stat"),
+        data_file_size: 4 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let stat = engine.stat().unwrap();
+    assert_eq!(0, stat.key_num);
+    assert_eq!(1, stat.data_file_num);
+    assert_eq!(0, stat.reclaimable_size);
+
+    for i in 0..50000 {
+        engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+    }
+    let stat = engine.stat().unwrap();
+    assert_eq!(50000, stat.key_num);
+    assert!(stat.data_file_num > 1);
+    assert_eq!(stat.data_file_num, stat.data_files.len());
+    assert_eq!(0, stat.reclaimable_size);
+    let total_size: u64 = stat.data_files.iter().map(|f| f.total_size).sum();
+    assert!(stat.disk_size >= total_size);
+
+    //覆盖写和批量写入产生的失效数据
+    for i in 0..10000 {
+        engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+    }
+    let wb = engine.new_write_batch(WriteBatchOptions::default());
+    for i in 10000..11000 {
+        wb.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+    }
+    wb.commit().expect("failed to commit");
+    let stat = engine.stat().unwrap();
+    assert_eq!(50000, stat.key_num);
+    assert!(stat.reclaimable_size > 0);
+    for file_stat in stat.data_files.iter() {
+        assert!(file_stat.reclaimable_size <= file_stat.total_size);
+    }
+
+    //重启后加载索引时统计的结果相同
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    let stat2 = engine2.stat().unwrap();
+    assert_eq!(stat.key_num, stat2.key_num);
+    assert_eq!(stat.data_file_num, stat2.data_file_num);
+    assert_eq!(stat.reclaimable_size, stat2.reclaimable_size);
+
+    //删除数据,数据和墓碑值都可以回收
+    engine2.delete(get_test_key(20000)).expect("failed to delete");
+    let stat3 = engine2.stat().unwrap();
+    assert_eq!(49999, stat3.key_num);
+    assert!(stat3.reclaimable_size > stat2.reclaimable_size);
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_open_with_torn_tail() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-torn-tail"),
+        data_file_size: 4 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+    }
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+
+    //模拟写入过程中崩溃,最后一条记录只写入了一半
+    let data_file_name = get_data_file_name(&opts.dir_path, 0);
+    let valid_size = std::fs::metadata(&data_file_name).unwrap().len();
+    let torn_record = LogRecord {
+        key: log_record_key_with_seq(get_test_key(1000).to_vec(), NON_TRANSACTION_SEQ_NO),
+        value: get_test_value(1000).to_vec(),
+        rec_type: LogRecordType::NORMAL,
+        expire_at: 0,
+    }
+    .encode();
+    let mut file = OpenOptions::new().append(true).open(&data_file_name).unwrap();
+    file.write_all(&torn_record[..torn_record.len() / 2]).unwrap();
+    std::mem::drop(file);
+
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(valid_size, std::fs::metadata(&data_file_name).unwrap().len());
+    assert_eq!(1000, engine2.list_keys().len());
+    assert_eq!(Errors::KeyNotFound, engine2.get(get_test_key(1000)).err().unwrap());
+    //截断之后写入的数据在重启后可以正常读取
+    engine2.put(get_test_key(1000), Bytes::from("after torn write")).expect("failed to put");
+    engine2.close().expect("failed to close");
+    std::mem::drop(engine2);
+
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(1001, engine3.list_keys().len());
+    assert_eq!(Bytes::from("after torn write"), engine3.get(get_test_key(1000)).unwrap());
+    for i in 0..1000 {
+        assert_eq!(get_test_value(i), engine3.get(get_test_key(i)).unwrap());
+    }
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_open_with_corrupted_tail() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-corrupted-tail"),
+        data_file_size: 4 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+    }
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+
+    //最后一条记录的长度完整,但是内容没有完全落盘,crc校验不通过
+    let data_file_name = get_data_file_name(&opts.dir_path, 0);
+    let mut content = std::fs::read(&data_file_name).unwrap();
+    let len = content.len();
+    content[len - 10] ^= 0xff;
+    std::fs::write(&data_file_name, &content).unwrap();
+
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(999, engine2.list_keys().len());
+    assert_eq!(Errors::KeyNotFound, engine2.get(get_test_key(999)).err().unwrap());
+    assert!(std::fs::metadata(&data_file_name).unwrap().len() < len as u64);
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+//写入数据后在第一个数据文件的中间制造一条损坏的记录
+fn prepare_corrupted_db(dir_path: &str) -> Options {
+    let opts = Options {
+        dir_path: PathBuf::from(dir_path),
+        data_file_size: 64 * 1024,
+        ..Default::default()
+    };
+    //上一次失败的测试可能留下了损坏的数据
+    let _ = std::fs::remove_dir_all(&opts.dir_path);
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+    }
+    assert!(engine.stat().unwrap().data_file_num > 2);
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+
+    let data_file_name = get_data_file_name(&opts.dir_path, 0);
+    let mut content = std::fs::read(&data_file_name).unwrap();
+    let mid = content.len() / 2;
+    content[mid] ^= 0xff;
+    std::fs::write(&data_file_name, &content).unwrap();
+    opts
+}
+
+#[test]
+fn test_engine_recovery_strict() {
+    let opts = prepare_corrupted_db("/tmp/bitcask-rs-recovery-strict");
+    let res = Engine::open(opts.clone());
+    assert!(res.is_err());
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_recovery_skip_corrupted() {
+    let mut opts = prepare_corrupted_db("/tmp/bitcask-rs-recovery-skip");
+    opts.recovery_mode = RecoveryMode::SkipCorrupted;
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let lost_ranges = &engine.recovery_report().lost_ranges;
+    assert_eq!(1, lost_ranges.len());
+    assert_eq!(0, lost_ranges[0].file_id);
+    assert!(lost_ranges[0].start < lost_ranges[0].end);
+    //只丢失了损坏的那条记录
+    let keys = engine.list_keys();
+    assert_eq!(1999, keys.len());
+    for key in keys {
+        assert!(engine.get(key).is_ok());
+    }
+    assert!(engine.stat().unwrap().reclaimable_size > 0);
+
+    //merge会跳过损坏的数据
+    engine.merge().expect("failed to merge");
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine2.recovery_report().lost_ranges.is_empty());
+    assert_eq!(1999, engine2.list_keys().len());
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_recovery_stop_at_first_corruption() {
+    let mut opts = prepare_corrupted_db("/tmp/bitcask-rs-recovery-stop");
+    opts.recovery_mode = RecoveryMode::StopAtFirstCorruption;
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    //第一个文件损坏位置之后的数据和后面的数据文件都被丢弃
+    let stat = engine.stat().unwrap();
+    assert_eq!(1, stat.data_file_num);
+    let lost_ranges = &engine.recovery_report().lost_ranges;
+    assert!(lost_ranges.len() > 2);
+    assert!(lost_ranges.iter().all(|r| r.start < r.end));
+    //丢弃的数据都保存在.corrupt文件中
+    let quarantined_files = &engine.recovery_report().quarantined_files;
+    assert_eq!(lost_ranges.len(), quarantined_files.len());
+    for (range, file) in lost_ranges.iter().zip(quarantined_files.iter()) {
+        assert!(file.to_str().unwrap().ends_with(".data.corrupt"));
+        assert_eq!(range.end - range.start, std::fs::metadata(file).unwrap().len());
+    }
+    let key_num = engine.list_keys().len();
+    assert!(key_num > 0 && key_num < 1000);
+    for i in 0..key_num as i32 {
+        assert_eq!(get_test_value(i), engine.get(get_test_key(i)).unwrap());
+    }
+
+    //之后写入的数据在重启后不会丢失
+    engine.put(Bytes::from("new key"), Bytes::from("new value")).expect("failed to put");
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine2.recovery_report().lost_ranges.is_empty());
+    assert_eq!(key_num + 1, engine2.list_keys().len());
+    assert_eq!(Bytes::from("new value"), engine2.get(Bytes::from("new key")).unwrap());
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_compare_and_swap() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-cas"),
+        data_file_size: 64 * 1024 * 1024,
+        sync_writes: false,
+        index_type: IndexType::BTree,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let key = Bytes::from("cas");
+
+    //1.key不存在时写入
+    assert_eq!(Ok(true), engine.put_if_absent(key.clone(), Bytes::from("v1")));
+    assert_eq!(Ok(false), engine.put_if_absent(key.clone(), Bytes::from("v2")));
+    assert_eq!(Bytes::from("v1"), engine.get(key.clone()).unwrap());
+
+    //2.当前值不等于expected时不写入
+    let res = engine.compare_and_swap(key.clone(), Some(Bytes::from("v0")), Some(Bytes::from("v2")));
+    assert_eq!(Ok(false), res);
+    let res = engine.compare_and_swap(key.clone(), Some(Bytes::from("v1")), Some(Bytes::from("v2")));
+    assert_eq!(Ok(true), res);
+    assert_eq!(Bytes::from("v2"), engine.get(key.clone()).unwrap());
+
+    //3.值相等时才删除
+    assert_eq!(Ok(false), engine.delete_if_equals(key.clone(), Bytes::from("v1")));
+    assert_eq!(Ok(true), engine.delete_if_equals(key.clone(), Bytes::from("v2")));
+    assert_eq!(Err(Errors::KeyNotFound), engine.get(key.clone()));
+    assert_eq!(Ok(false), engine.delete_if_equals(key.clone(), Bytes::from("v2")));
+    assert_eq!(Err(Errors::KeyIsEmpty), engine.put_if_absent(Bytes::new(), Bytes::from("v1")));
+
+    //4.并发的compare_and_swap不会丢失更新
+    engine.put(key.clone(), Bytes::from("0")).unwrap();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut done = 0;
+                while done < 100 {
+                    let current = engine.get(key.clone()).unwrap();
+                    let n: u32 = std::str::from_utf8(&current).unwrap().parse().unwrap();
+                    let new = Bytes::from((n + 1).to_string());
+                    if engine.compare_and_swap(key.clone(), Some(current), Some(new)).unwrap() {
+                        done += 1;
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(Bytes::from("400"), engine.get(key.clone()).unwrap());
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_compression() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-compression"),
+        data_file_size: 64 * 1024 * 1024,
+        sync_writes: false,
+        index_type: IndexType::BTree,
+        ..Default::default()
+    };
+    let json_value = |i: i32| Bytes::from(format!(r#"{{"id":{},"name":"bitcask-rs","tags":["kv","storage","kv","storage"]}}"#, i).repeat(4));
+    let mut engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        engine.put(get_test_key(i), json_value(i)).unwrap();
+    }
+    let raw_size = engine.active_file.read().get_write_off();
+
+    //1.修改压缩算法之后,压缩和没有压缩的数据在同一个文件中
+    for (compression, start) in [(Compression::Lz4, 1000), (Compression::Snappy, 2000)] {
+        engine.close().expect("failed to close");
+        std::mem::drop(engine);
+        engine = Engine::open(Options { compression, ..opts.clone() }).expect("failed to open engine");
+        let write_off = engine.active_file.read().get_write_off();
+        for i in start..start + 1000 {
+            engine.put(get_test_key(i), json_value(i)).unwrap();
+        }
+        assert!(engine.active_file.read().get_write_off() - write_off < raw_size / 2);
+    }
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..3000 {
+        assert_eq!(json_value(i), engine.get(get_test_key(i)).unwrap());
+    }
+
+    //2.merge之后按照新的压缩算法重写数据
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine = Engine::open(Options { compression: Compression::Lz4, ..opts.clone() }).expect("failed to open engine");
+    engine.merge().expect("failed to merge");
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(3000, engine.list_keys().len());
+    assert_eq!(json_value(2999), engine.get(get_test_key(2999)).unwrap());
+    assert!(engine.stat().unwrap().data_files[0].total_size < raw_size * 3 / 2);
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_encryption() {
+    let key_ring = Arc::new(KeyRing::new(1, [1; 32]));
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-encryption"),
+        data_file_size: 64 * 1024 * 1024,
+        sync_writes: false,
+        index_type: IndexType::BTree,
+        compression: Compression::Lz4,
+        blob_value_threshold: Some(4096),
+        key_provider: Some(key_ring.clone()),
+        ..Default::default()
+    };
+    //磁盘上所有文件中都找不到明文
+    let contains_plaintext = |plaintext: &[u8]| {
+        std::fs::read_dir(&opts.dir_path).unwrap().any(|entry| {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            data.windows(plaintext.len()).any(|window| window == plaintext)
+        })
+    };
+    let large_value = Bytes::from("customer-secret-".repeat(1000));
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    engine.put(Bytes::from("customer-key"), Bytes::from("customer-value")).unwrap();
+    engine.put(Bytes::from("large"), large_value.clone()).unwrap();
+    for i in 0..100 {
+        engine.put(get_test_key(i), get_test_value(i)).unwrap();
+    }
+    engine.sync().unwrap();
+    assert!(!contains_plaintext(b"customer-key"));
+    assert!(!contains_plaintext(b"customer-value"));
+    assert!(!contains_plaintext(b"customer-secret-"));
+
+    //1.轮换密钥之后,旧密钥写入的数据仍然可以读取
+    key_ring.rotate(2, [2; 32]);
+    engine.put(Bytes::from("rotated"), Bytes::from("value")).unwrap();
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(Bytes::from("customer-value"), engine.get(Bytes::from("customer-key")).unwrap());
+    assert_eq!(large_value, engine.get(Bytes::from("large")).unwrap());
+    assert_eq!(Bytes::from("value"), engine.get(Bytes::from("rotated")).unwrap());
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+
+    //2.缺少密钥时无法打开数据库
+    let res = Engine::open(Options { key_provider: None, ..opts.clone() });
+    assert_eq!(Errors::EncryptionKeyNotFound, res.err().unwrap());
+    let only_new_key = Arc::new(KeyRing::new(2, [2; 32]));
+    let res = Engine::open(Options { key_provider: Some(only_new_key.clone()), ..opts.clone() });
+    assert_eq!(Errors::EncryptionKeyNotFound, res.err().unwrap());
+    let wrong_key = Arc::new(KeyRing::new(1, [3; 32]));
+    wrong_key.add_key(2, [2; 32]);
+    let res = Engine::open(Options { key_provider: Some(wrong_key), ..opts.clone() });
+    assert_eq!(Errors::FailedToDecryptLogRecord, res.err().unwrap());
+
+    //3.merge和gc_blobs之后有效数据都用新的密钥重写,不再需要旧的密钥
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    engine.merge().expect("failed to merge");
+    engine.put(Bytes::from("large"), Bytes::from("small")).unwrap();
+    engine.gc_blobs().expect("failed to gc blobs");
+    engine.gc_blobs().expect("failed to gc blobs");
+    engine.close().expect("failed to close");
+    std::mem::drop(engine);
+    assert!(!contains_plaintext(b"customer-key"));
+    let engine = Engine::open(Options { key_provider: Some(only_new_key), ..opts.clone() }).expect("failed to open engine");
+    assert_eq!(103, engine.list_keys().len());
+    assert_eq!(Bytes::from("customer-value"), engine.get(Bytes::from("customer-key")).unwrap());
+    assert_eq!(get_test_value(99), engine.get(get_test_key(99)).unwrap());
+
+    std::fs::remove_dir_all(&opts.dir_path).expect("failed to remove path");
 }
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use parking_lot::RwLock;
+
+use crate::errors::{Errors, Result};
+
+//加密数据文件使用的密钥,每个密钥有一个id,记录中保存了加密时使用的密钥id
+//轮换密钥之后旧的密钥仍然要能查到,之前写入的数据才能被读取,merge时会用当前的密钥重写有效的数据
+pub trait KeyProvider: Send + Sync {
+	//写入时使用的密钥id和密钥
+	fn current_key(&self) -> (u32, [u8; 32]);
+	//读取时根据记录中的id查找密钥,找不到时返回None
+	fn get_key(&self, key_id: u32) -> Option<[u8; 32]>;
+}
+
+//内存中的密钥集合,rotate之后新写入的数据使用新的密钥
+pub struct KeyRing {
+	keys: RwLock<HashMap<u32, [u8; 32]>>,
+	current_key_id: AtomicU32,
+}
+
+impl KeyRing {
+	pub fn new(key_id: u32, key: [u8; 32]) -> KeyRing {
+		KeyRing {
+			keys: RwLock::new(HashMap::from([(key_id, key)])),
+			current_key_id: AtomicU32::new(key_id),
+		}
+	}
+
+	//添加一个旧的密钥,只用于读取之前写入的数据
+	pub fn add_key(&self, key_id: u32, key: [u8; 32]) {
+		self.keys.write().insert(key_id, key);
+	}
+
+	//之后写入的数据使用新的密钥
+	pub fn rotate(&self, key_id: u32, key: [u8; 32]) {
+		self.add_key(key_id, key);
+		self.current_key_id.store(key_id, Ordering::SeqCst);
+	}
+}
+
+impl KeyProvider for KeyRing {
+	fn current_key(&self) -> (u32, [u8; 32]) {
+		let key_id = self.current_key_id.load(Ordering::SeqCst);
+		(key_id, self.keys.read()[&key_id])
+	}
+
+	fn get_key(&self, key_id: u32) -> Option<[u8; 32]> {
+		self.keys.read().get(&key_id).copied()
+	}
+}
+
+const KEY_ID_SIZE: usize = 4;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+//加密的记录在value后面追加的数据: 密钥id + nonce + tag
+pub(crate) const ENCRYPTION_TRAILER_SIZE: usize = KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE;
+
+//用当前的密钥原地加密payload,header作为附加数据一起认证,返回需要追加在payload后面的数据
+//每条记录使用随机生成的192位nonce,同一个密钥加密大量记录时nonce也不会重复
+pub(crate) fn encrypt(provider: &dyn KeyProvider, header: &[u8], payload: &mut [u8]) -> Result<Vec<u8>> {
+	let (key_id, key) = provider.current_key();
+	let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+	let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+	let tag = cipher
+		.encrypt_in_place_detached(&nonce, header, payload)
+		.map_err(|_| Errors::FailedToEncryptLogRecord)?;
+	let mut trailer = Vec::with_capacity(ENCRYPTION_TRAILER_SIZE);
+	trailer.extend_from_slice(&key_id.to_be_bytes());
+	trailer.extend_from_slice(&nonce);
+	trailer.extend_from_slice(&tag);
+	Ok(trailer)
+}
+
+//根据trailer中的密钥id找到密钥,原地解密payload并校验header和payload没有被篡改
+pub(crate) fn decrypt(
+	provider: Option<&dyn KeyProvider>,
+	header: &[u8],
+	payload: &mut [u8],
+	trailer: &[u8],
+) -> Result<()> {
+	if trailer.len() != ENCRYPTION_TRAILER_SIZE {
+		return Err(Errors::InvalidLogRecordHeader);
+	}
+	let (key_id, rest) = trailer.split_at(KEY_ID_SIZE);
+	let (nonce, tag) = rest.split_at(NONCE_SIZE);
+	let key_id = u32::from_be_bytes(key_id.try_into().unwrap());
+	let key = provider
+		.and_then(|provider| provider.get_key(key_id))
+		.ok_or(Errors::EncryptionKeyNotFound)?;
+	let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+	cipher
+		.decrypt_in_place_detached(XNonce::from_slice(nonce), header, payload, Tag::from_slice(tag))
+		.map_err(|_| Errors::FailedToDecryptLogRecord)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_encrypt_and_decrypt() {
+		let key_ring = KeyRing::new(1, [1; 32]);
+		let header = b"header".to_vec();
+		let plaintext = b"bitcask-rs".to_vec();
+		let mut payload = plaintext.clone();
+		let trailer = encrypt(&key_ring, &header, &mut payload).unwrap();
+		assert_eq!(ENCRYPTION_TRAILER_SIZE, trailer.len());
+		assert_ne!(plaintext, payload);
+
+		//轮换之后旧的密钥仍然可以解密
+		key_ring.rotate(2, [2; 32]);
+		let mut decrypted = payload.clone();
+		assert!(decrypt(Some(&key_ring), &header, &mut decrypted, &trailer).is_ok());
+		assert_eq!(plaintext, decrypted);
+
+		//header被修改,密钥不存在或者不对时都无法解密
+		let mut decrypted = payload.clone();
+		assert_eq!(Err(Errors::FailedToDecryptLogRecord), decrypt(Some(&key_ring), b"other", &mut decrypted, &trailer));
+		assert_eq!(Err(Errors::EncryptionKeyNotFound), decrypt(None, &header, &mut decrypted, &trailer));
+		let other = KeyRing::new(1, [3; 32]);
+		assert_eq!(Err(Errors::FailedToDecryptLogRecord), decrypt(Some(&other), &header, &mut decrypted, &trailer));
+		let other = KeyRing::new(3, [1; 32]);
+		assert_eq!(Err(Errors::EncryptionKeyNotFound), decrypt(Some(&other), &header, &mut decrypted, &trailer));
+	}
+}
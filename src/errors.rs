@@ -1,4 +1,3 @@
-use log::error;
 use std::result;
 use thiserror::Error;
 
@@ -36,6 +35,50 @@ pub enum Errors {
     ReadDataFileEOF,
     #[error("invalid crc value,log record maybe corrupted")]
     InvalidLogRecordCrc,
+    #[error("log record is incomplete, the last write maybe interrupted")]
+    IncompleteLogRecord,
+    #[error("invalid log record header, log record maybe corrupted")]
+    InvalidLogRecordHeader,
+    #[error("unknown log record type")]
+    UnknownLogRecordType,
+    #[error("failed to decompress the value of log record")]
+    FailedToDecompressValue,
+    #[error("the encryption key of log record is not found in the key provider")]
+    EncryptionKeyNotFound,
+    #[error("failed to encrypt log record")]
+    FailedToEncryptLogRecord,
+    #[error("failed to decrypt log record, the key is wrong or the record is tampered")]
+    FailedToDecryptLogRecord,
+    #[error("invalid log record key, failed to decode the seq no")]
+    InvalidLogRecordKey,
+    #[error("invalid log record pos in hint file")]
+    InvalidLogRecordPos,
     #[error("exceed the max batch num")]
     ExceedMaxBatchNum,
+    #[error("no savepoint in the write batch to rollback to")]
+    NoSavepoint,
+    #[error("write batch spilled to disk can't rollback to savepoint")]
+    SpilledBatchNotRevertible,
+    #[error("merge operator is not configured in options")]
+    MergeOperatorNotFound,
+    #[error("transaction conflict, the keys read by the transaction have been modified")]
+    TransactionConflict,
+    #[error("merge is in progress, try again later")]
+    MergeInProgress,
+    #[error("there are write batches spilled to disk but not committed, try merge later")]
+    SpilledBatchInProgress,
+    #[error("failed to apply merged data files")]
+    FailedToApplyMergeFiles,
+    #[error("merged data files exceed the file ids reserved for them, merge aborted")]
+    TooManyMergedFiles,
+    #[error("invalid merge ratio, must between 0 and 1")]
+    InvalidMergeRatio,
+    #[error("invalid merge check interval, must be greater than 0")]
+    InvalidMergeCheckInterval,
+    #[error("invalid blob gc ratio, must between 0 and 1")]
+    InvalidBlobGcRatio,
+    #[error("the database directory is used by another process")]
+    DatabaseIsUsing,
+    #[error("the database is closed")]
+    DatabaseClosed,
 }
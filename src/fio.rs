@@ -12,6 +12,10 @@ pub trait IOManager: Sync + Send {
     fn write(&self, buf: &[u8]) -> Result<usize>;
     //sync持久化数据
     fn sync(&self) -> Result<()>;
+    //文件的大小
+    fn size(&self) -> u64;
+    //把文件截断到指定的大小
+    fn truncate(&self, size: u64) -> Result<()>;
 }
 
 //根据文件名称初始化IOManager,目前只实现了文件IO
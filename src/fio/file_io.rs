@@ -1,7 +1,7 @@
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::os::unix::prelude::FileExt;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::Arc;
 
 use log::error;
@@ -17,6 +17,7 @@ pub struct FileIO {
 //数据文件(DataFile)调用实现了IOManager的结构体的相关方法进行IO
 impl FileIO {
     //文件名称的路径
+    #[allow(clippy::ineffective_open_options)]
     pub fn new(file_name: &Path) -> Result<Self> {
         match OpenOptions::new()
             .create(true)
@@ -71,11 +72,32 @@ impl IOManager for FileIO {
         }
         Ok(())
     }
+
+    fn size(&self) -> u64 {
+        let read_guard = self.fd.read();
+        match read_guard.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                error!("failed to get data file metadata:{}", e);
+                0
+            }
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let write_guard = self.fd.write();
+        if let Err(e) = write_guard.set_len(size) {
+            error!("failed to truncate data file:{}", e);
+            return Err(Errors::FailedToWriteToDataFile);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::fs;
+    use std::path::PathBuf;
 
     use super::*;
 
@@ -126,4 +148,29 @@ mod test {
         let res3 = fs::remove_file(path.clone());
         assert!(res3.is_ok()); //记住测试完成后删除测试生成的文件
     }
+
+    #[test]
+    fn test_file_io_size() {
+        let path = PathBuf::from("/tmp/a.data3");
+        let fio = FileIO::new(&path).unwrap();
+        assert_eq!(0, fio.size());
+        fio.write("key-a\n".as_bytes()).unwrap();
+        assert_eq!(6, fio.size());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_io_truncate() {
+        let path = PathBuf::from("/tmp/a.data4");
+        let fio = FileIO::new(&path).unwrap();
+        fio.write("key-a\n".as_bytes()).unwrap();
+        assert!(fio.truncate(3).is_ok());
+        assert_eq!(3, fio.size());
+        //文件是以append的方式打开的,截断之后从新的末尾开始写
+        fio.write("bc".as_bytes()).unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(5, fio.read(&mut buf, 0).unwrap());
+        assert_eq!("keybc".as_bytes(), &buf);
+        fs::remove_file(path).unwrap();
+    }
 }
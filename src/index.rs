@@ -1,29 +1,37 @@
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
 use bytes::Bytes;
 
 use crate::data::log_record::LogRecordPos;
 use crate::options::{IndexType, IteratorOptions};
 
 pub mod btree;
+pub mod skiplist;
 
 //Indexer 抽象数据接口，后续如果想要接入其他数据结构，则可以实现这个trait即可
 //这个Indexer是内存索引的组织结构,可以有红黑树,BTree,跳表(这些都是天然有序的可以遍历),哈希表(无序,不推荐使用)
-pub trait Indexer {
-    //向索引中存储key对应的数据位置信息
-    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> bool;
+pub trait Indexer: Sync + Send {
+    //向索引中存储key对应的数据位置信息,返回被覆盖的旧位置信息
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos>;
     //根据key取出对应的索引位置信息
     fn get(&self, keys: Vec<u8>) -> Option<LogRecordPos>;
-    //Delete根据key删除对应的索引位置信息
-    fn delete(&self, key: Vec<u8>) -> bool;
+    //Delete根据key删除对应的索引位置信息,返回被删除的位置信息
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos>;
     fn iterator(&self, opts: &IteratorOptions) -> Box<dyn IndexIterator>;
     fn list_keys(&self) -> Vec<Bytes>;
+    //索引中key的数量
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 //根据类型打开内存索引
-pub fn new_indexer(index_type: IndexType) -> Box<dyn Indexer> {
+pub fn new_indexer(index_type: IndexType) -> Arc<dyn Indexer> {
     match index_type {
-        IndexType::BTree => Box::new(btree::Btree::new()),
-        IndexType::SkipList => todo!(),
-        _ => panic!("unknown index type"),
+        IndexType::BTree => Arc::new(btree::Btree::new()),
+        IndexType::SkipList => Arc::new(skiplist::SkipList::new()),
     }
 }
 //Iterator要有的操作
@@ -34,4 +42,210 @@ pub trait IndexIterator: Sync + Send {
     fn seek(&mut self, key: Vec<u8>);
     //next跳转到下一个key,返回None则代表迭代完成
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
+    //prev往回移动一位,返回上一次next返回的数据,返回None则代表已经回到起点
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
+    //从迭代范围的另一端开始往回迭代,和next相遇之后返回None
+    fn next_back(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
+}
+
+//索引迭代器的游标,btree和跳表的迭代器共用
+//迭代器不再拷贝整个索引,每次移动都从游标的位置做一次范围查找,只取出一条数据
+//迭代过程中索引被修改时,同一个方向上返回的key仍然严格有序且不会重复
+pub(crate) struct IterCursor<V = LogRecordPos> {
+    reverse: bool,
+    //prefix和用户指定的上下界合并后的迭代范围
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    //next的游标,在迭代方向上next从这里往后查找,prev从这里往前查找
+    front: Bound<Vec<u8>>,
+    //next_back的游标,从迭代范围的末尾往前查找
+    back: Bound<Vec<u8>>,
+    //上一次返回的数据
+    current: Option<(Vec<u8>, V)>,
+}
+
+impl<V> IterCursor<V> {
+    pub(crate) fn new(options: &IteratorOptions) -> Self {
+        //以prefix开头的key都在[prefix, prefix_upper_bound)的范围内
+        let (mut lower, mut upper) = (Bound::Unbounded, Bound::Unbounded);
+        if !options.prefix.is_empty() {
+            lower = Bound::Included(options.prefix.clone());
+            if let Some(end) = prefix_upper_bound(&options.prefix) {
+                upper = Bound::Excluded(end);
+            }
+        }
+        let mut cursor = IterCursor {
+            reverse: options.reverse,
+            lower: tighter_lower(lower, options.lower_bound.clone()),
+            upper: tighter_upper(upper, options.upper_bound.clone()),
+            front: Bound::Unbounded,
+            back: Bound::Unbounded,
+            current: None,
+        };
+        cursor.rewind();
+        cursor
+    }
+
+    //回到迭代范围的起点
+    pub(crate) fn rewind(&mut self) {
+        (self.front, self.back) = match self.reverse {
+            false => (self.lower.clone(), self.upper.clone()),
+            true => (self.upper.clone(), self.lower.clone()),
+        };
+        self.current = None;
+    }
+
+    //从第一个大于(反向时为小于)等于key的位置开始,key在迭代范围之外时从范围的起点开始
+    pub(crate) fn seek(&mut self, key: Vec<u8>) {
+        self.rewind();
+        self.front = match self.reverse {
+            false => tighter_lower(self.lower.clone(), Bound::Included(key)),
+            true => tighter_upper(self.upper.clone(), Bound::Included(key)),
+        };
+    }
+
+    //下面的lookup(bound, ascending)在索引中查找一条数据:
+    //ascending为true时返回满足下界bound的最小的key,否则返回满足上界bound的最大的key
+    pub(crate) fn next<F>(&mut self, lookup: F) -> Option<(&Vec<u8>, &V)>
+    where
+        F: FnOnce(Bound<&[u8]>, bool) -> Option<(Vec<u8>, V)>,
+    {
+        let item = lookup(as_slice_bound(&self.front), !self.reverse)?;
+        //数据是有序的,一旦超出了迭代范围,后面的数据也都不满足,不用再继续查找
+        if !self.in_range(&item.0) || !satisfies(&item.0, &self.back, self.reverse) {
+            return None;
+        }
+        self.front = Bound::Excluded(item.0.clone());
+        self.set_current(item)
+    }
+
+    pub(crate) fn prev<F>(&mut self, lookup: F) -> Option<(&Vec<u8>, &V)>
+    where
+        F: FnOnce(Bound<&[u8]>, bool) -> Option<(Vec<u8>, V)>,
+    {
+        //游标前面的数据,游标停在x之后时x本身也在游标前面
+        let bound = match &self.front {
+            Bound::Included(key) => Bound::Excluded(key.as_slice()),
+            Bound::Excluded(key) => Bound::Included(key.as_slice()),
+            Bound::Unbounded => return None,
+        };
+        let item = lookup(bound, self.reverse)?;
+        if !self.in_range(&item.0) {
+            return None;
+        }
+        //之后的next会再次返回这条数据
+        self.front = Bound::Included(item.0.clone());
+        self.set_current(item)
+    }
+
+    pub(crate) fn next_back<F>(&mut self, lookup: F) -> Option<(&Vec<u8>, &V)>
+    where
+        F: FnOnce(Bound<&[u8]>, bool) -> Option<(Vec<u8>, V)>,
+    {
+        let item = lookup(as_slice_bound(&self.back), self.reverse)?;
+        if !self.in_range(&item.0) || !satisfies(&item.0, &self.front, !self.reverse) {
+            return None;
+        }
+        self.back = Bound::Excluded(item.0.clone());
+        self.set_current(item)
+    }
+
+    //把游标移动到key之后,不改变next_back的位置
+    pub(crate) fn skip_past(&mut self, key: Vec<u8>) {
+        self.front = Bound::Excluded(key);
+    }
+
+    pub(crate) fn is_reverse(&self) -> bool {
+        self.reverse
+    }
+
+    fn set_current(&mut self, item: (Vec<u8>, V)) -> Option<(&Vec<u8>, &V)> {
+        self.current = Some(item);
+        self.current.as_ref().map(|(key, pos)| (key, pos))
+    }
+
+    fn in_range(&self, key: &Vec<u8>) -> bool {
+        RangeBounds::<Vec<u8>>::contains(&(self.lower.as_ref(), self.upper.as_ref()), key)
+    }
+}
+
+fn as_slice_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    bound.as_ref().map(|key| key.as_slice())
+}
+
+//ascending为true时判断key是否满足下界bound,否则判断是否满足上界bound
+fn satisfies(key: &Vec<u8>, bound: &Bound<Vec<u8>>, ascending: bool) -> bool {
+    match (bound, ascending) {
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(b), true) => key >= b,
+        (Bound::Excluded(b), true) => key > b,
+        (Bound::Included(b), false) => key <= b,
+        (Bound::Excluded(b), false) => key < b,
+    }
+}
+
+//以prefix开头的key都小于返回的值,prefix为空或者全是0xff时没有这样的值
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+//取两个下界中范围更小的那个
+fn tighter_lower(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x > y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+//取两个上界中范围更小的那个
+fn tighter_upper(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x < y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(None, prefix_upper_bound(&[]));
+        assert_eq!(Some(b"ab".to_vec()), prefix_upper_bound(b"aa"));
+        assert_eq!(Some(vec![1, 3]), prefix_upper_bound(&[1, 2, 0xff]));
+        assert_eq!(None, prefix_upper_bound(&[0xff, 0xff]));
+    }
+
+    #[test]
+    fn test_tighter_bound() {
+        let (a, b) = (b"a".to_vec(), b"b".to_vec());
+        assert_eq!(Bound::Included(a.clone()), tighter_lower(Bound::Unbounded, Bound::Included(a.clone())));
+        assert_eq!(Bound::Included(b.clone()), tighter_lower(Bound::Included(a.clone()), Bound::Included(b.clone())));
+        assert_eq!(Bound::Excluded(a.clone()), tighter_lower(Bound::Included(a.clone()), Bound::Excluded(a.clone())));
+        assert_eq!(Bound::Excluded(b.clone()), tighter_upper(Bound::Unbounded, Bound::Excluded(b.clone())));
+        assert_eq!(Bound::Included(a.clone()), tighter_upper(Bound::Included(a.clone()), Bound::Included(b.clone())));
+        assert_eq!(Bound::Excluded(b.clone()), tighter_upper(Bound::Excluded(b.clone()), Bound::Included(b)));
+    }
 }
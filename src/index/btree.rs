@@ -1,12 +1,12 @@
 use std::collections::BTreeMap;
+use std::ops::Bound;
 use std::sync::Arc;
 
 use bytes::Bytes;
 use parking_lot::RwLock;
 
 use crate::data::log_record::LogRecordPos;
-use crate::errors::Result;
-use crate::index::{IndexIterator, Indexer};
+use crate::index::{IndexIterator, Indexer, IterCursor};
 use crate::options::IteratorOptions;
 
 //对标准库BtreeMap简单封装,用读写锁和Arc来包装,读写锁保证了线程安全,Arc可以让多个线程拥有其所有权
@@ -22,13 +22,18 @@ impl Btree {
     }
 }
 
+impl Default for Btree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 //为什么使用&self而不是&mut self呢?
 impl Indexer for Btree {
-    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> bool {
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
         //这里利用了内部可变性
         let mut writer_guard = self.tree.write();
-        writer_guard.insert(key, pos);
-        true
+        writer_guard.insert(key, pos)
     }
 
     fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
@@ -36,26 +41,15 @@ impl Indexer for Btree {
         read_guard.get(&key).cloned()
     }
 
-    fn delete(&self, key: Vec<u8>) -> bool {
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
         let mut writer_guard = self.tree.write();
-        writer_guard.remove(&key).is_some()
+        writer_guard.remove(&key)
     }
-    //为Btree创建迭代器可能会导致内存膨胀
-    //这里迭代器的实现比较简单粗暴,把所有的key和对应的LogRecordPos加入到一个Vec里面,维护这个Vec的index
+    //迭代器持有btree的引用,每次next时才去btree中查找下一条数据
     fn iterator(&self, opts: &IteratorOptions) -> Box<dyn IndexIterator> {
-        let read_guard = self.tree.read();
-        let mut items = Vec::with_capacity(read_guard.len());
-        //将Btree的数据存在数组中
-        for (key, value) in read_guard.iter() {
-            items.push((key.clone(), value.clone()));
-        }
-        if opts.reverse {
-            items.reverse();
-        }
         Box::new(BTreeIterator {
-            items,
-            curr_index: 0,
-            options: opts.clone(),
+            tree: self.tree.clone(),
+            cursor: IterCursor::new(opts),
         })
     }
     //返回装有所有key的Vec
@@ -67,47 +61,50 @@ impl Indexer for Btree {
         }
         keys
     }
+
+    fn len(&self) -> usize {
+        self.tree.read().len()
+    }
 }
 
 pub struct BTreeIterator {
-    items: Vec<(Vec<u8>, LogRecordPos)>,
-    //这是有序的
-    curr_index: usize,
-    options: IteratorOptions,
+    tree: Arc<RwLock<BTreeMap<Vec<u8>, LogRecordPos>>>,
+    cursor: IterCursor,
 }
 
 impl IndexIterator for BTreeIterator {
     fn rewind(&mut self) {
-        self.curr_index = 0
+        self.cursor.rewind();
     }
     fn seek(&mut self, key: Vec<u8>) {
-        //二分查找key所在的位置
-        let res = self.items.binary_search_by(|(x, _)| {
-            if self.options.reverse {
-                x.cmp(&key).reverse()
-            } else {
-                x.cmp(&key)
-            }
-        });
-        //这个函数如果找到返回OK(pos),否则返回Err(pos)(这里pos为要插入的位置)
-        self.curr_index = res.unwrap_or_else(|pos| pos);
+        self.cursor.seek(key);
     }
-    //iterator向前移动一位
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
-        if self.curr_index >= self.items.len() {
-            return None;
-        }
-        while let Some(item) = self.items.get(self.curr_index) {
-            self.curr_index += 1;
-            let prefix = &self.options.prefix;
-            //用户没有指定prefix或者我们已经找到了以prefix开头的数据,则可以返回
-            if prefix.is_empty() || item.0.starts_with(prefix) {
-                return Some((&item.0, &item.1));
-            }
-        }
-        //遍历结束都没找到满足prefix的数据
-        None
+        let tree = &self.tree;
+        self.cursor.next(|bound, ascending| lookup(tree, bound, ascending))
+    }
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let tree = &self.tree;
+        self.cursor.prev(|bound, ascending| lookup(tree, bound, ascending))
     }
+    fn next_back(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let tree = &self.tree;
+        self.cursor.next_back(|bound, ascending| lookup(tree, bound, ascending))
+    }
+}
+
+//查找满足边界的第一条数据,只在查找时持有读锁,不会长时间阻塞写入
+fn lookup(
+    tree: &RwLock<BTreeMap<Vec<u8>, LogRecordPos>>,
+    bound: Bound<&[u8]>,
+    ascending: bool,
+) -> Option<(Vec<u8>, LogRecordPos)> {
+    let read_guard = tree.read();
+    let item = match ascending {
+        true => read_guard.range::<[u8], _>((bound, Bound::Unbounded)).next(),
+        false => read_guard.range::<[u8], _>((Bound::Unbounded, bound)).next_back(),
+    };
+    item.map(|(key, pos)| (key.clone(), *pos))
 }
 
 #[cfg(test)]
@@ -116,43 +113,57 @@ mod test {
 
     #[test]
     fn test_btree_put() {
-        let mut bt = Btree::new();
+        let bt = Btree::new();
         //&str类型的as_bytes得到&[u8],调用to_vec()方法得到Vec<u8>
         let res = bt.put(
             "vec![1,2]".as_bytes().to_vec(),
             LogRecordPos {
                 file_id: 1,
                 offset: 2,
+                size: 11,
             },
         );
-        assert_eq!(res, true);
+        assert!(res.is_none());
         let res = bt.put(
             "aa".as_bytes().to_vec(),
             LogRecordPos {
                 file_id: 1,
                 offset: 10,
+                size: 11,
             },
         );
-        assert_eq!(res, true);
+        assert!(res.is_none());
         let res = bt.put(
             "aaa".as_bytes().to_vec(),
             LogRecordPos {
                 file_id: 1,
                 offset: 20,
+                size: 11,
             },
         );
-        assert_eq!(res, true);
+        assert!(res.is_none());
+        //覆盖已有的key,返回旧的位置信息
+        let res = bt.put(
+            "aa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 2,
+                offset: 30,
+                size: 11,
+            },
+        );
+        assert_eq!(10, res.unwrap().offset);
         // println!("{:#?}",bt.get("aa".as_bytes().to_vec()));
     }
 
     #[test]
     fn test_btree_get() {
-        let mut bt = Btree::new();
+        let bt = Btree::new();
         bt.put(
             "vec![1,2]".as_bytes().to_vec(),
             LogRecordPos {
                 file_id: 1,
                 offset: 2,
+                size: 11,
             },
         );
         let res = bt.get("vec![1,2]".as_bytes().to_vec());
@@ -162,16 +173,19 @@ mod test {
 
     #[test]
     fn test_btree_delete() {
-        let mut bt = Btree::new();
+        let bt = Btree::new();
         bt.put(
             "vec![1,2]".as_bytes().to_vec(),
             LogRecordPos {
                 file_id: 1,
                 offset: 2,
+                size: 11,
             },
         );
-        assert_eq!(bt.delete("vec![1,2]".as_bytes().to_vec()), true);
-        assert_eq!(bt.delete("vec![1,2]".as_bytes().to_vec()), false);
+        assert_eq!(1, bt.len());
+        assert!(bt.delete("vec![1,2]".as_bytes().to_vec()).is_some());
+        assert!(bt.delete("vec![1,2]".as_bytes().to_vec()).is_none());
+        assert_eq!(0, bt.len());
     }
 
     #[test]
@@ -187,6 +201,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 10,
+                size: 11,
             },
         );
         let mut iter2 = bt.iterator(&IteratorOptions::default());
@@ -203,6 +218,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 20,
+                size: 11,
             },
         );
         bt.put(
@@ -210,6 +226,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 20,
+                size: 11,
             },
         );
         bt.put(
@@ -217,6 +234,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 20,
+                size: 11,
             },
         );
         let mut iter = bt.iterator(&IteratorOptions::default());
@@ -228,12 +246,12 @@ mod test {
         let mut iter = bt.iterator(&IteratorOptions::default());
         iter.seek("aawe".as_bytes().to_vec());
         assert_eq!(iter.next().unwrap().0.to_vec(), "aawe".as_bytes().to_vec());
-        let mut iter = bt.iterator(&IteratorOptions::default());
         //反向迭代
 
         let mut iter = bt.iterator(&IteratorOptions {
             prefix: vec![],
             reverse: true,
+            ..Default::default()
         });
         iter.seek("cdsa".as_bytes().to_vec());
         while let Some(item) = iter.next() {
@@ -243,6 +261,7 @@ mod test {
 
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_btree_iterator_next() {
         let bt = Btree::new();
         let mut iter1 = bt.iterator(&IteratorOptions::default());
@@ -254,6 +273,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 10,
+                size: 11,
             },
         );
         let mut iter_opt1 = IteratorOptions::default();
@@ -267,6 +287,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 10,
+                size: 11,
             },
         );
         bt.put(
@@ -274,6 +295,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 10,
+                size: 11,
             },
         );
         bt.put(
@@ -281,6 +303,7 @@ mod test {
             LogRecordPos {
                 file_id: 1,
                 offset: 10,
+                size: 11,
             },
         );
 
@@ -288,7 +311,7 @@ mod test {
         iter_opt2.reverse = true;
         let mut iter3 = bt.iterator(&iter_opt2);
         while let Some(item) = iter3.next() {
-            assert!(item.0.len() > 0);
+            assert!(!item.0.is_empty());
         }
 
         // 有前缀的情况
@@ -296,8 +319,181 @@ mod test {
         iter_opt3.prefix = "bbed".as_bytes().to_vec();
         let mut iter4 = bt.iterator(&iter_opt3);
         while let Some(item) = iter4.next() {
-            assert!(item.0.len() > 0);
+            assert!(!item.0.is_empty());
         }
     }
 
+
+    #[test]
+    fn test_btree_iterator_prefix() {
+        let bt = Btree::new();
+        for key in ["aa", "ab", "abc", "abd", "ac", "b"] {
+            bt.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                    size: 11,
+                },
+            );
+        }
+        let collect = |iter: &mut Box<dyn IndexIterator>| {
+            let mut keys = vec![];
+            while let Some((key, _)) = iter.next() {
+                keys.push(String::from_utf8(key.clone()).unwrap());
+            }
+            keys
+        };
+        let mut iter = bt.iterator(&IteratorOptions {
+            prefix: "ab".as_bytes().to_vec(),
+            ..Default::default()
+        });
+        assert_eq!(vec!["ab", "abc", "abd"], collect(&mut iter));
+        //seek到prefix范围之前的key,从prefix范围的起点开始
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!(vec!["ab", "abc", "abd"], collect(&mut iter));
+        iter.seek("abd".as_bytes().to_vec());
+        assert_eq!(vec!["abd"], collect(&mut iter));
+        iter.seek("ac".as_bytes().to_vec());
+        assert!(iter.next().is_none());
+
+        //反向迭代
+        let mut iter = bt.iterator(&IteratorOptions {
+            prefix: "ab".as_bytes().to_vec(),
+            reverse: true,
+            ..Default::default()
+        });
+        assert_eq!(vec!["abd", "abc", "ab"], collect(&mut iter));
+        iter.seek("zz".as_bytes().to_vec());
+        assert_eq!(vec!["abd", "abc", "ab"], collect(&mut iter));
+        iter.seek("abc".as_bytes().to_vec());
+        assert_eq!(vec!["abc", "ab"], collect(&mut iter));
+
+        //迭代器不是索引的拷贝,迭代过程中写入的数据也能被遍历到
+        let mut iter = bt.iterator(&IteratorOptions::default());
+        assert_eq!("aa".as_bytes(), iter.next().unwrap().0.as_slice());
+        bt.put(
+            "aaa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        bt.delete("ab".as_bytes().to_vec());
+        assert_eq!(vec!["aaa", "abc", "abd", "ac", "b"], collect(&mut iter));
+        iter.rewind();
+        assert_eq!(6, collect(&mut iter).len());
+    }
+
+    #[test]
+    fn test_btree_iterator_bounds() {
+        let bt = Btree::new();
+        for key in ["a", "b", "c", "d", "e"] {
+            bt.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                    size: 11,
+                },
+            );
+        }
+        let collect = |opts: IteratorOptions| {
+            let mut iter = bt.iterator(&opts);
+            let mut keys = vec![];
+            while let Some((key, _)) = iter.next() {
+                keys.push(String::from_utf8(key.clone()).unwrap());
+            }
+            keys
+        };
+        //[b, d)
+        let opts = IteratorOptions {
+            lower_bound: Bound::Included("b".as_bytes().to_vec()),
+            upper_bound: Bound::Excluded("d".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["b", "c"], collect(opts.clone()));
+        assert_eq!(vec!["c", "b"], collect(IteratorOptions { reverse: true, ..opts }));
+        //(b, d]
+        let opts = IteratorOptions {
+            lower_bound: Bound::Excluded("b".as_bytes().to_vec()),
+            upper_bound: Bound::Included("d".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["c", "d"], collect(opts.clone()));
+        assert_eq!(vec!["d", "c"], collect(IteratorOptions { reverse: true, ..opts }));
+        //只有一边的边界
+        let opts = IteratorOptions {
+            lower_bound: Bound::Included("cc".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["d", "e"], collect(opts));
+        //空的范围
+        let opts = IteratorOptions {
+            lower_bound: Bound::Included("d".as_bytes().to_vec()),
+            upper_bound: Bound::Excluded("b".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert!(collect(opts.clone()).is_empty());
+        assert!(collect(IteratorOptions { reverse: true, ..opts }).is_empty());
+
+        //seek到范围之外的key
+        let mut iter = bt.iterator(&IteratorOptions {
+            lower_bound: Bound::Included("b".as_bytes().to_vec()),
+            upper_bound: Bound::Included("d".as_bytes().to_vec()),
+            ..Default::default()
+        });
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!("b".as_bytes(), iter.next().unwrap().0.as_slice());
+        iter.seek("dd".as_bytes().to_vec());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_btree_iterator_prev_and_next_back() {
+        let bt = Btree::new();
+        for key in ["a", "b", "c", "d"] {
+            bt.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                    size: 11,
+                },
+            );
+        }
+        let key = |item: Option<(&Vec<u8>, &LogRecordPos)>| item.map(|(k, _)| String::from_utf8(k.clone()).unwrap());
+        let mut iter = bt.iterator(&IteratorOptions {
+            upper_bound: Bound::Excluded("d".as_bytes().to_vec()),
+            ..Default::default()
+        });
+        assert_eq!(None, key(iter.prev()));
+        assert_eq!(Some("a".to_string()), key(iter.next()));
+        assert_eq!(Some("b".to_string()), key(iter.next()));
+        assert_eq!(Some("b".to_string()), key(iter.prev()));
+        assert_eq!(Some("a".to_string()), key(iter.prev()));
+        assert_eq!(None, key(iter.prev()));
+        assert_eq!(Some("a".to_string()), key(iter.next()));
+        //next_back从范围的末尾开始,和next相遇后结束
+        assert_eq!(Some("c".to_string()), key(iter.next_back()));
+        assert_eq!(Some("b".to_string()), key(iter.next_back()));
+        assert_eq!(None, key(iter.next_back()));
+        assert_eq!(None, key(iter.next()));
+
+        let mut iter = bt.iterator(&IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        });
+        assert_eq!(Some("a".to_string()), key(iter.next_back()));
+        assert_eq!(Some("d".to_string()), key(iter.next()));
+        assert_eq!(Some("d".to_string()), key(iter.prev()));
+        //seek会同时重置next_back的位置
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(Some("c".to_string()), key(iter.prev()));
+        assert_eq!(Some("c".to_string()), key(iter.next()));
+        assert_eq!(Some("b".to_string()), key(iter.next()));
+        assert_eq!(Some("a".to_string()), key(iter.next()));
+        assert_eq!(None, key(iter.next()));
+    }
 }
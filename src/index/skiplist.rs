@@ -0,0 +1,547 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+use parking_lot::Mutex;
+
+use crate::data::log_record::LogRecordPos;
+use crate::index::{IndexIterator, Indexer, IterCursor};
+use crate::options::IteratorOptions;
+
+//写入锁的数量,不同的key大多落在不同的锁上
+const WRITE_LOCK_NUM: usize = 64;
+
+//对crossbeam的无锁跳表简单封装,读不需要加锁,并发读写时不会互相阻塞
+pub struct SkipList {
+    skl: Arc<SkipMap<Vec<u8>, LogRecordPos>>,
+    //跳表的insert不会返回旧值,写入时先取旧值再插入,用key对应的锁保证两步之间不会有同一个key的其他写入
+    write_locks: Vec<Mutex<()>>,
+    hasher: RandomState,
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        SkipList {
+            skl: Arc::new(SkipMap::new()),
+            write_locks: (0..WRITE_LOCK_NUM).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn write_lock(&self, key: &[u8]) -> &Mutex<()> {
+        &self.write_locks[self.hasher.hash_one(key) as usize % WRITE_LOCK_NUM]
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Indexer for SkipList {
+    fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> Option<LogRecordPos> {
+        //返回的旧值决定了哪条数据可以回收,两个写入不能拿到同一个旧值
+        let _lock = self.write_lock(&key).lock();
+        let old_pos = self.skl.get(&key).map(|entry| *entry.value());
+        self.skl.insert(key, pos);
+        old_pos
+    }
+
+    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        self.skl.get(&key).map(|entry| *entry.value())
+    }
+
+    fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        let _lock = self.write_lock(&key).lock();
+        self.skl.remove(&key).map(|entry| *entry.value())
+    }
+    //迭代器持有跳表的引用,每次next时才去跳表中查找下一条数据
+    fn iterator(&self, opts: &IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(SkipListIterator {
+            skl: self.skl.clone(),
+            cursor: IterCursor::new(opts),
+        })
+    }
+    //返回装有所有key的Vec
+    fn list_keys(&self) -> Vec<Bytes> {
+        let mut keys = Vec::with_capacity(self.skl.len());
+        for entry in self.skl.iter() {
+            keys.push(Bytes::copy_from_slice(entry.key()));
+        }
+        keys
+    }
+
+    fn len(&self) -> usize {
+        self.skl.len()
+    }
+}
+
+pub struct SkipListIterator {
+    skl: Arc<SkipMap<Vec<u8>, LogRecordPos>>,
+    cursor: IterCursor,
+}
+
+impl IndexIterator for SkipListIterator {
+    fn rewind(&mut self) {
+        self.cursor.rewind();
+    }
+    fn seek(&mut self, key: Vec<u8>) {
+        self.cursor.seek(key);
+    }
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let skl = &self.skl;
+        self.cursor.next(|bound, ascending| lookup(skl, bound, ascending))
+    }
+    fn prev(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let skl = &self.skl;
+        self.cursor.prev(|bound, ascending| lookup(skl, bound, ascending))
+    }
+    fn next_back(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        let skl = &self.skl;
+        self.cursor.next_back(|bound, ascending| lookup(skl, bound, ascending))
+    }
+}
+
+//查找满足边界的第一条数据
+fn lookup(
+    skl: &SkipMap<Vec<u8>, LogRecordPos>,
+    bound: Bound<&[u8]>,
+    ascending: bool,
+) -> Option<(Vec<u8>, LogRecordPos)> {
+    let entry = match ascending {
+        true => skl.lower_bound(bound),
+        false => skl.upper_bound(bound),
+    };
+    entry.map(|entry| (entry.key().clone(), *entry.value()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_skl_put() {
+        let skl = SkipList::new();
+        //&str类型的as_bytes得到&[u8],调用to_vec()方法得到Vec<u8>
+        let res = skl.put(
+            "vec![1,2]".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 2,
+                size: 11,
+            },
+        );
+        assert!(res.is_none());
+        let res = skl.put(
+            "aa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        assert!(res.is_none());
+        let res = skl.put(
+            "aaa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 20,
+                size: 11,
+            },
+        );
+        assert!(res.is_none());
+        //覆盖已有的key,返回旧的位置信息
+        let res = skl.put(
+            "aa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 2,
+                offset: 30,
+                size: 11,
+            },
+        );
+        assert_eq!(10, res.unwrap().offset);
+    }
+
+    #[test]
+    fn test_skl_put_concurrently() {
+        let skl = SkipList::new();
+        //并发覆盖同一个key,每个位置只能作为旧值被返回一次
+        let mut offsets: Vec<u64> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4u64)
+                .map(|t| {
+                    let skl = &skl;
+                    s.spawn(move || {
+                        (0..1000u64)
+                            .filter_map(|i| {
+                                let pos = LogRecordPos {
+                                    file_id: 1,
+                                    offset: t * 1000 + i,
+                                    size: 11,
+                                };
+                                skl.put("aa".as_bytes().to_vec(), pos).map(|old| old.offset)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+        offsets.push(skl.get("aa".as_bytes().to_vec()).unwrap().offset);
+        offsets.sort();
+        assert_eq!((0..4000).collect::<Vec<u64>>(), offsets);
+    }
+
+    #[test]
+    fn test_skl_get() {
+        let skl = SkipList::new();
+        skl.put(
+            "vec![1,2]".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 2,
+                size: 11,
+            },
+        );
+        let res = skl.get("vec![1,2]".as_bytes().to_vec());
+        assert!(res.is_some());
+        assert!(res.unwrap().file_id == 1 && res.unwrap().offset == 2);
+    }
+
+    #[test]
+    fn test_skl_delete() {
+        let skl = SkipList::new();
+        skl.put(
+            "vec![1,2]".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 2,
+                size: 11,
+            },
+        );
+        assert_eq!(1, skl.len());
+        assert!(skl.delete("vec![1,2]".as_bytes().to_vec()).is_some());
+        assert!(skl.delete("vec![1,2]".as_bytes().to_vec()).is_none());
+        assert_eq!(0, skl.len());
+    }
+
+    #[test]
+    fn test_skl_iterator_seek() {
+        let skl = SkipList::new();
+        let mut iter1 = skl.iterator(&IteratorOptions::default());
+        iter1.seek("aaa".as_bytes().to_vec());
+        let res = iter1.next();
+        assert!(res.is_none());
+        //有一条数据的情况
+        skl.put(
+            "ccde".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        let mut iter2 = skl.iterator(&IteratorOptions::default());
+        iter2.seek("aa".as_bytes().to_vec());
+        let res = iter2.next();
+        assert!(res.is_some());
+        //seek一条不存在的数据
+        let mut iter3 = skl.iterator(&IteratorOptions::default());
+        iter3.seek("zz".as_bytes().to_vec());
+        assert!(iter3.next().is_none());
+        //有多条数据的情况
+        skl.put(
+            "ba".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 20,
+                size: 11,
+            },
+        );
+        skl.put(
+            "aawe".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 20,
+                size: 11,
+            },
+        );
+        skl.put(
+            "cdsa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 20,
+                size: 11,
+            },
+        );
+        let mut iter = skl.iterator(&IteratorOptions::default());
+        iter.seek("b".as_bytes().to_vec());
+        //没有以a开头的数据
+        let mut keys = vec![];
+        while let Some(item) = iter.next() {
+            keys.push(item.0.to_vec());
+        }
+        assert_eq!(keys, vec![b"ba".to_vec(), b"ccde".to_vec(), b"cdsa".to_vec()]);
+        let mut iter = skl.iterator(&IteratorOptions::default());
+        iter.seek("aawe".as_bytes().to_vec());
+        assert_eq!(iter.next().unwrap().0.to_vec(), "aawe".as_bytes().to_vec());
+        //反向迭代
+
+        let mut iter = skl.iterator(&IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        });
+        iter.seek("cdsa".as_bytes().to_vec());
+        let mut keys = vec![];
+        while let Some(item) = iter.next() {
+            keys.push(item.0.to_vec());
+        }
+        assert_eq!(keys, vec![b"cdsa".to_vec(), b"ccde".to_vec(), b"ba".to_vec(), b"aawe".to_vec()]);
+    }
+
+
+    #[test]
+    fn test_skl_iterator_next() {
+        let skl = SkipList::new();
+        let mut iter1 = skl.iterator(&IteratorOptions::default());
+        assert!(iter1.next().is_none());
+
+        // 有一条数据的情况
+        skl.put(
+            "cadd".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        let iter_opt1 = IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        };
+        let mut iter2 = skl.iterator(&iter_opt1);
+        assert!(iter2.next().is_some());
+
+        // 有多条数据的情况
+        skl.put(
+            "bbed".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        skl.put(
+            "aaed".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        skl.put(
+            "cdea".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+
+        let iter_opt2 = IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        };
+        let mut iter3 = skl.iterator(&iter_opt2);
+        while let Some(item) = iter3.next() {
+            assert!(!item.0.is_empty());
+        }
+
+        // 有前缀的情况
+        let iter_opt3 = IteratorOptions {
+            prefix: "bbed".as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let mut iter4 = skl.iterator(&iter_opt3);
+        while let Some(item) = iter4.next() {
+            assert!(!item.0.is_empty());
+        }
+    }
+
+
+    #[test]
+    fn test_skl_iterator_prefix() {
+        let skl = SkipList::new();
+        for key in ["aa", "ab", "abc", "abd", "ac", "b"] {
+            skl.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                    size: 11,
+                },
+            );
+        }
+        let collect = |iter: &mut Box<dyn IndexIterator>| {
+            let mut keys = vec![];
+            while let Some((key, _)) = iter.next() {
+                keys.push(String::from_utf8(key.clone()).unwrap());
+            }
+            keys
+        };
+        let mut iter = skl.iterator(&IteratorOptions {
+            prefix: "ab".as_bytes().to_vec(),
+            ..Default::default()
+        });
+        assert_eq!(vec!["ab", "abc", "abd"], collect(&mut iter));
+        //seek到prefix范围之前的key,从prefix范围的起点开始
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!(vec!["ab", "abc", "abd"], collect(&mut iter));
+        iter.seek("abd".as_bytes().to_vec());
+        assert_eq!(vec!["abd"], collect(&mut iter));
+        iter.seek("ac".as_bytes().to_vec());
+        assert!(iter.next().is_none());
+
+        //反向迭代
+        let mut iter = skl.iterator(&IteratorOptions {
+            prefix: "ab".as_bytes().to_vec(),
+            reverse: true,
+            ..Default::default()
+        });
+        assert_eq!(vec!["abd", "abc", "ab"], collect(&mut iter));
+        iter.seek("zz".as_bytes().to_vec());
+        assert_eq!(vec!["abd", "abc", "ab"], collect(&mut iter));
+        iter.seek("abc".as_bytes().to_vec());
+        assert_eq!(vec!["abc", "ab"], collect(&mut iter));
+
+        //迭代器不是索引的拷贝,迭代过程中写入的数据也能被遍历到
+        let mut iter = skl.iterator(&IteratorOptions::default());
+        assert_eq!("aa".as_bytes(), iter.next().unwrap().0.as_slice());
+        skl.put(
+            "aaa".as_bytes().to_vec(),
+            LogRecordPos {
+                file_id: 1,
+                offset: 10,
+                size: 11,
+            },
+        );
+        skl.delete("ab".as_bytes().to_vec());
+        assert_eq!(vec!["aaa", "abc", "abd", "ac", "b"], collect(&mut iter));
+        iter.rewind();
+        assert_eq!(6, collect(&mut iter).len());
+    }
+
+    #[test]
+    fn test_skl_iterator_bounds() {
+        let skl = SkipList::new();
+        for key in ["a", "b", "c", "d", "e"] {
+            skl.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                    size: 11,
+                },
+            );
+        }
+        let collect = |opts: IteratorOptions| {
+            let mut iter = skl.iterator(&opts);
+            let mut keys = vec![];
+            while let Some((key, _)) = iter.next() {
+                keys.push(String::from_utf8(key.clone()).unwrap());
+            }
+            keys
+        };
+        //[b, d)
+        let opts = IteratorOptions {
+            lower_bound: Bound::Included("b".as_bytes().to_vec()),
+            upper_bound: Bound::Excluded("d".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["b", "c"], collect(opts.clone()));
+        assert_eq!(vec!["c", "b"], collect(IteratorOptions { reverse: true, ..opts }));
+        //(b, d]
+        let opts = IteratorOptions {
+            lower_bound: Bound::Excluded("b".as_bytes().to_vec()),
+            upper_bound: Bound::Included("d".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["c", "d"], collect(opts.clone()));
+        assert_eq!(vec!["d", "c"], collect(IteratorOptions { reverse: true, ..opts }));
+        //只有一边的边界
+        let opts = IteratorOptions {
+            lower_bound: Bound::Included("cc".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["d", "e"], collect(opts));
+        //空的范围
+        let opts = IteratorOptions {
+            lower_bound: Bound::Included("d".as_bytes().to_vec()),
+            upper_bound: Bound::Excluded("b".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        assert!(collect(opts.clone()).is_empty());
+        assert!(collect(IteratorOptions { reverse: true, ..opts }).is_empty());
+
+        //seek到范围之外的key
+        let mut iter = skl.iterator(&IteratorOptions {
+            lower_bound: Bound::Included("b".as_bytes().to_vec()),
+            upper_bound: Bound::Included("d".as_bytes().to_vec()),
+            ..Default::default()
+        });
+        iter.seek("a".as_bytes().to_vec());
+        assert_eq!("b".as_bytes(), iter.next().unwrap().0.as_slice());
+        iter.seek("dd".as_bytes().to_vec());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_skl_iterator_prev_and_next_back() {
+        let skl = SkipList::new();
+        for key in ["a", "b", "c", "d"] {
+            skl.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                    size: 11,
+                },
+            );
+        }
+        let key = |item: Option<(&Vec<u8>, &LogRecordPos)>| item.map(|(k, _)| String::from_utf8(k.clone()).unwrap());
+        let mut iter = skl.iterator(&IteratorOptions {
+            upper_bound: Bound::Excluded("d".as_bytes().to_vec()),
+            ..Default::default()
+        });
+        assert_eq!(None, key(iter.prev()));
+        assert_eq!(Some("a".to_string()), key(iter.next()));
+        assert_eq!(Some("b".to_string()), key(iter.next()));
+        assert_eq!(Some("b".to_string()), key(iter.prev()));
+        assert_eq!(Some("a".to_string()), key(iter.prev()));
+        assert_eq!(None, key(iter.prev()));
+        assert_eq!(Some("a".to_string()), key(iter.next()));
+        //next_back从范围的末尾开始,和next相遇后结束
+        assert_eq!(Some("c".to_string()), key(iter.next_back()));
+        assert_eq!(Some("b".to_string()), key(iter.next_back()));
+        assert_eq!(None, key(iter.next_back()));
+        assert_eq!(None, key(iter.next()));
+
+        let mut iter = skl.iterator(&IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        });
+        assert_eq!(Some("a".to_string()), key(iter.next_back()));
+        assert_eq!(Some("d".to_string()), key(iter.next()));
+        assert_eq!(Some("d".to_string()), key(iter.prev()));
+        //seek会同时重置next_back的位置
+        iter.seek("b".as_bytes().to_vec());
+        assert_eq!(Some("c".to_string()), key(iter.prev()));
+        assert_eq!(Some("c".to_string()), key(iter.next()));
+        assert_eq!(Some("b".to_string()), key(iter.next()));
+        assert_eq!(Some("a".to_string()), key(iter.next()));
+        assert_eq!(None, key(iter.next()));
+    }
+}
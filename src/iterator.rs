@@ -1,29 +1,50 @@
-use std::sync::Arc;
-
 use bytes::Bytes;
-use parking_lot::RwLock;
 
+use crate::data::log_record::LogRecordPos;
 use crate::db::Engine;
-use crate::errors::Result;
+use crate::errors::{Errors, Result};
 use crate::index::IndexIterator;
 use crate::options::IteratorOptions;
+use crate::ttl::now_millis;
 //'a 表示 engine 的引用至少与 Iterator 实例有相同的生命周期。
+//实现了标准库的Iterator和DoubleEndedIterator,可以直接使用take,filter,collect,rev等方法
 pub struct Iterator<'a> {
-	index_iter: Arc<RwLock<Box<dyn IndexIterator>>>,
+	key_iter: KeyIterator<'a>,
+}
+
+//只遍历内存索引的迭代器,返回key和一个延迟读取value的句柄,只需要key的时候不会读取数据文件
+pub struct KeyIterator<'a> {
+	index_iter: Box<dyn IndexIterator>,
+	engine: &'a Engine,
+}
+
+//value在数据文件中的位置,调用value()时才去数据文件中读取
+#[derive(Clone, Copy)]
+pub struct LazyValue<'a> {
+	pos: LogRecordPos,
 	engine: &'a Engine,
 }
 
 impl Engine {
 	//获取迭代器
-	pub fn iter(&self, iterator_options: &IteratorOptions) -> Iterator {
+	pub fn iter(&self, iterator_options: &IteratorOptions) -> Iterator<'_> {
 		Iterator {
-			index_iter: Arc::new(RwLock::new(self.indexer.iterator(iterator_options))),
+			key_iter: self.key_iter(iterator_options),
+		}
+	}
+	//获取只遍历key的迭代器
+	pub fn key_iter(&self, iterator_options: &IteratorOptions) -> KeyIterator<'_> {
+		KeyIterator {
+			index_iter: self.indexer.iterator(iterator_options),
 			engine: self,
 		}
 	}
-	//返回数据库中所有的key
+	//返回数据库中所有的key,不包括已经过期的key
 	pub fn list_keys(&self) -> Vec<Bytes> {
-		self.indexer.list_keys()
+		if self.expirations.is_empty() {
+			return self.indexer.list_keys();
+		}
+		self.key_iter(&IteratorOptions::default()).map(|(key, _)| key).collect()
 	}
 	//对数据库当中的所有数据进行函数操作,如果函数返回false则终止
 	pub fn fold<F>(&self, f: F) -> Result<()>
@@ -31,8 +52,8 @@ impl Engine {
 			Self: Sized,
 			F: Fn(Bytes, Bytes) -> bool,
 	{
-		let iter = self.iter(&IteratorOptions::default());
-		while let Some((key, value)) = iter.next() {
+		for item in self.iter(&IteratorOptions::default()) {
+			let (key, value) = item?;
 			if !f(key, value) {
 				break;
 			}
@@ -44,59 +65,135 @@ impl Engine {
 
 //编译器推导生命周期
 impl Iterator<'_> {
-	pub fn rewind(&self) {
-		let mut index_iter = self.index_iter.write();
-		index_iter.rewind();
-	}
-	pub fn seek(&self, key: Vec<u8>) {
-		let mut index_iter = self.index_iter.write();
-		index_iter.seek(key);
-	}
-	pub fn next(&self) -> Option<(Bytes, Bytes)> {
-		let mut index_iter = self.index_iter.write();
-		if let Some(item) = index_iter.next() {
-			let value = self
-				.engine
-				.get_value_by_position(item.1.clone())
-				.expect("failed to get value from data file");
-			return Some((Bytes::from(item.0.to_owned()), value));
+	pub fn rewind(&mut self) {
+		self.key_iter.rewind();
+	}
+	pub fn seek(&mut self, key: Vec<u8>) {
+		self.key_iter.seek(key);
+	}
+	//往回移动一位,返回上一次next返回的数据
+	pub fn prev(&mut self) -> Option<Result<(Bytes, Bytes)>> {
+		next_value(|| self.key_iter.prev())
+	}
+}
+
+impl std::iter::Iterator for Iterator<'_> {
+	type Item = Result<(Bytes, Bytes)>;
+
+	//读取value失败时返回错误,而不是直接panic
+	fn next(&mut self) -> Option<Self::Item> {
+		next_value(|| self.key_iter.next())
+	}
+}
+
+impl DoubleEndedIterator for Iterator<'_> {
+	fn next_back(&mut self) -> Option<Self::Item> {
+		next_value(|| self.key_iter.next_back())
+	}
+}
+
+//KeyIterator检查过期之后读取value之前key可能刚好过期,这时跳过这个key
+fn next_value<'a>(mut advance: impl FnMut() -> Option<(Bytes, LazyValue<'a>)>) -> Option<Result<(Bytes, Bytes)>> {
+	loop {
+		let (key, value) = advance()?;
+		match value.value() {
+			Err(Errors::KeyNotFound) => continue,
+			value => return Some(value.map(|value| (key, value))),
 		}
-		None
+	}
+}
+
+impl<'a> KeyIterator<'a> {
+	pub fn rewind(&mut self) {
+		self.index_iter.rewind();
+	}
+	pub fn seek(&mut self, key: Vec<u8>) {
+		self.index_iter.seek(key);
+	}
+	//往回移动一位,返回上一次next返回的数据
+	pub fn prev(&mut self) -> Option<(Bytes, LazyValue<'a>)> {
+		loop {
+			let item = to_item(self.engine, self.index_iter.prev()?);
+			if !item.1.is_expired() {
+				return Some(item);
+			}
+		}
+	}
+}
+
+impl<'a> std::iter::Iterator for KeyIterator<'a> {
+	type Item = (Bytes, LazyValue<'a>);
+
+	//跳过已经过期的key
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let item = to_item(self.engine, self.index_iter.next()?);
+			if !item.1.is_expired() {
+				return Some(item);
+			}
+		}
+	}
+}
+
+impl DoubleEndedIterator for KeyIterator<'_> {
+	fn next_back(&mut self) -> Option<Self::Item> {
+		loop {
+			let item = to_item(self.engine, self.index_iter.next_back()?);
+			if !item.1.is_expired() {
+				return Some(item);
+			}
+		}
+	}
+}
+
+fn to_item<'a>(engine: &'a Engine, (key, pos): (&Vec<u8>, &LogRecordPos)) -> (Bytes, LazyValue<'a>) {
+	(Bytes::from(key.to_owned()), LazyValue { pos: *pos, engine })
+}
+
+impl LazyValue<'_> {
+	//根据索引里的位置信息读出value
+	pub fn value(&self) -> Result<Bytes> {
+		self.engine.get_value_by_position(self.pos)
+	}
+	fn is_expired(&self) -> bool {
+		self.engine.expirations.is_expired(self.pos, now_millis())
 	}
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
-    use std::path::PathBuf;
+	use std::fs;
+	use std::ops::Bound;
+	use std::path::PathBuf;
 
-    use crate::options::{IndexType, Options};
-    use crate::util;
+	use crate::options::{IndexType, Options};
+	use crate::util;
 
-    use super::*;
+	use super::*;
 
-    #[test]
+	#[test]
 	fn test_iterator_seek() {
 		let opts = Options {
 			dir_path: PathBuf::from("/tmp/bitcask-rs-iter-seek"),
 			data_file_size: 256 * 1024 * 1024,
 			sync_writes: false,
 			index_type: IndexType::BTree,
+			..Default::default()
 		};
 
 		let engine = Engine::open(opts).expect("failed to open engine");
-		let iter = engine.iter(&IteratorOptions::default());
+		let mut iter = engine.iter(&IteratorOptions::default());
 		iter.seek("aa".as_bytes().to_vec());
 		assert!(iter.next().is_none());
 
 		engine
 			.put(Bytes::from("aacc"), util::rand_kv::get_test_value(10))
 			.expect("Engine failed to put");
-		let iter = engine.iter(&IteratorOptions::default());
+		let mut iter = engine.iter(&IteratorOptions::default());
 		iter.seek("a".as_bytes().to_vec());
 		let res = iter.next();
 		assert!(res.is_some());
-		println!("{:?}", res.unwrap());
+		println!("{:?}", res.unwrap().unwrap());
 
 		engine
 			.put(Bytes::from("abbb"), util::rand_kv::get_test_value(10))
@@ -107,9 +204,10 @@ mod tests {
 		engine
 			.put(Bytes::from("abbb"), util::rand_kv::get_test_value(30))
 			.expect("Engine failed to put");
-		let iter = engine.iter(&IteratorOptions::default());
+		let mut iter = engine.iter(&IteratorOptions::default());
 		iter.seek("a".as_bytes().to_vec());
-		while let Some((key, value)) = iter.next() {
+		for item in iter {
+			let (key, value) = item.expect("failed to read value");
 			println!("{:?}:{:?}", key, value);
 		}
 		//删除临时文件
@@ -119,24 +217,145 @@ mod tests {
 
 	#[test]
 	fn test_iterator_next() {
-		let mut opts = Options {
-			dir_path: PathBuf::from("/tmp/bitcask-rs-iter-seek"),
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-iter-next"),
 			data_file_size: 256 * 1024 * 1024,
 			sync_writes: false,
 			index_type: IndexType::BTree,
+			..Default::default()
 		};
 
 		let engine = Engine::open(opts).expect("failed to open engine");
 		engine
 			.put(Bytes::from("accc"), util::rand_kv::get_test_value(20))
 			.expect("failed to put");
-		let iter = engine.iter(&IteratorOptions::default()); //每一个iter都是一个数据库里面数据的副本
+		let mut iter = engine.iter(&IteratorOptions::default()); //iter不会拷贝索引,每次next时才去索引中查找
 		assert!(iter.next().is_some());
 		assert!(iter.next().is_none());
 		iter.rewind();
 		assert!(iter.next().is_some());
 		assert!(iter.next().is_none());
-		fs::remove_dir_all(PathBuf::from("/tmp/bitcask-rs-iter-seek"))
+		fs::remove_dir_all(PathBuf::from("/tmp/bitcask-rs-iter-next"))
+			.expect("failed to remove the dir");
+	}
+
+	#[test]
+	fn test_iterator_bounds() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-iter-bounds"),
+			data_file_size: 256 * 1024 * 1024,
+			sync_writes: false,
+			index_type: IndexType::SkipList,
+			..Default::default()
+		};
+
+		let engine = Engine::open(opts).expect("failed to open engine");
+		for i in 0..100 {
+			engine
+				.put(util::rand_kv::get_test_key(i), util::rand_kv::get_test_value(i))
+				.expect("failed to put");
+		}
+		//[key-10, key-20)
+		let iter = engine.iter(&IteratorOptions {
+			lower_bound: Bound::Included(util::rand_kv::get_test_key(10).to_vec()),
+			upper_bound: Bound::Excluded(util::rand_kv::get_test_key(20).to_vec()),
+			..Default::default()
+		});
+		let keys: Vec<Bytes> = iter.map(|item| item.expect("failed to read value").0).collect();
+		let expected: Vec<Bytes> = (10..20).map(util::rand_kv::get_test_key).collect();
+		assert_eq!(expected, keys);
+		fs::remove_dir_all(PathBuf::from("/tmp/bitcask-rs-iter-bounds"))
+			.expect("failed to remove the dir");
+	}
+
+	#[test]
+	fn test_iterator_adapters_and_prev() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-iter-adapters"),
+			data_file_size: 256 * 1024 * 1024,
+			sync_writes: false,
+			..Default::default()
+		};
+
+		let engine = Engine::open(opts).expect("failed to open engine");
+		for key in ["a", "b", "c", "d", "e"] {
+			engine
+				.put(Bytes::from(key), Bytes::from(key.repeat(2)))
+				.expect("failed to put");
+		}
+		fn keys(iter: impl std::iter::Iterator<Item = Result<(Bytes, Bytes)>>) -> Vec<Bytes> {
+			iter.map(|item| item.expect("failed to read value").0).collect()
+		}
+		//标准库的迭代器方法
+		let res: Result<Vec<(Bytes, Bytes)>> = engine.iter(&IteratorOptions::default()).take(2).collect();
+		assert_eq!(vec![(Bytes::from("a"), Bytes::from("aa")), (Bytes::from("b"), Bytes::from("bb"))], res.unwrap());
+		assert_eq!(vec!["e", "d", "c", "b", "a"], keys(engine.iter(&IteratorOptions::default()).rev()));
+
+		//两端同时迭代,相遇之后结束
+		let mut iter = engine.iter(&IteratorOptions::default());
+		assert_eq!(Bytes::from("a"), iter.next().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("e"), iter.next_back().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("d"), iter.next_back().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("b"), iter.next().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("c"), iter.next().unwrap().unwrap().0);
+		assert!(iter.next().is_none());
+		assert!(iter.next_back().is_none());
+
+		//往回移动
+		let mut iter = engine.iter(&IteratorOptions {
+			reverse: true,
+			..Default::default()
+		});
+		assert!(iter.prev().is_none());
+		assert_eq!(Bytes::from("e"), iter.next().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("d"), iter.next().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("d"), iter.prev().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("e"), iter.prev().unwrap().unwrap().0);
+		assert!(iter.prev().is_none());
+		iter.seek("c".as_bytes().to_vec());
+		assert_eq!(Bytes::from("d"), iter.prev().unwrap().unwrap().0);
+		assert_eq!(vec!["d", "c", "b", "a"], keys(iter));
+
+		fs::remove_dir_all(PathBuf::from("/tmp/bitcask-rs-iter-adapters"))
+			.expect("failed to remove the dir");
+	}
+
+	#[test]
+	fn test_key_iterator() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-iter-keys"),
+			data_file_size: 256 * 1024 * 1024,
+			sync_writes: false,
+			..Default::default()
+		};
+
+		let engine = Engine::open(opts).expect("failed to open engine");
+		for key in ["a", "b", "c"] {
+			engine
+				.put(Bytes::from(key), Bytes::from(key.repeat(2)))
+				.expect("failed to put");
+		}
+		let keys: Vec<Bytes> = engine.key_iter(&IteratorOptions::default()).map(|(key, _)| key).collect();
+		assert_eq!(vec!["a", "b", "c"], keys);
+
+		//需要的时候再读取value
+		let mut iter = engine.key_iter(&IteratorOptions {
+			reverse: true,
+			..Default::default()
+		});
+		let (key, value) = iter.next().unwrap();
+		assert_eq!(Bytes::from("c"), key);
+		assert_eq!(Bytes::from("cc"), value.value().unwrap());
+		let (key, _) = iter.next_back().unwrap();
+		assert_eq!(Bytes::from("a"), key);
+		let (key, value) = iter.prev().unwrap();
+		assert_eq!(Bytes::from("c"), key);
+		//句柄在迭代器移动之后仍然可以读取
+		iter.seek("b".as_bytes().to_vec());
+		assert_eq!(Bytes::from("b"), iter.next().unwrap().0);
+		assert_eq!(Bytes::from("cc"), value.value().unwrap());
+
+		fs::remove_dir_all(PathBuf::from("/tmp/bitcask-rs-iter-keys"))
 			.expect("failed to remove the dir");
 	}
 }
@@ -7,6 +7,13 @@ pub mod options;
 pub mod util;
 
 mod batch;
+mod blob;
 #[cfg(test)]
 mod db_test;
+pub mod encryption;
 pub mod iterator;
+mod merge;
+pub mod merge_operator;
+pub mod snapshot;
+pub mod transaction;
+mod ttl;
@@ -0,0 +1,836 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::{error, info, warn};
+use parking_lot::{Mutex, RwLock};
+
+use crate::batch::{log_record_key_with_seq, NON_TRANSACTION_SEQ_NO, parse_log_record_key};
+use crate::blob::BlobStore;
+use crate::data::data_file::{DataFile, get_data_file_name, HINT_FILE_NAME, MERGE_FINISHED_FILE_NAME};
+use crate::data::log_record::{decode_blob_hint, decode_log_record_pos, LogRecord, LogRecordPos, LogRecordType, ReadLogRecord};
+use crate::db::{Engine, is_corrupted_record_err, read_log_record_at};
+use crate::errors::{Errors, Result};
+use crate::index::Indexer;
+use crate::merge_operator::{OperandLinks, resolve_operands};
+use crate::ttl::now_millis;
+use crate::options::{Options, RecoveryMode};
+
+const MERGE_DIR_NAME: &str = "merge";
+const MERGE_FIN_KEY: &[u8] = "merge.finished".as_bytes();
+
+//merge需要用到的引擎内部状态,和Engine共享同一份数据
+//后台merge线程不能持有Engine的引用,所以持有一份MergeContext的clone
+#[derive(Clone)]
+pub(crate) struct MergeContext {
+	pub(crate) options: Arc<Options>,
+	pub(crate) active_file: Arc<RwLock<DataFile>>,
+	pub(crate) older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
+	pub(crate) indexer: Arc<dyn Indexer>,
+	pub(crate) batch_commit_lock: Arc<RwLock<()>>,
+	pub(crate) reclaimable_sizes: Arc<RwLock<HashMap<u32, u64>>>,
+	pub(crate) operand_links: Arc<OperandLinks>,
+	pub(crate) blob_store: Arc<BlobStore>,
+	//保证同一时刻只有一个merge在进行
+	merging_lock: Arc<Mutex<()>>,
+	//id比这个值小的文件已经merge过了,要等到重启时才会被替换,计算是否需要merge时不再统计
+	merged_file_id: Arc<AtomicU32>,
+	//已经把数据写入数据文件但还没有提交的batch的数量
+	pub(crate) spilling_batches: Arc<AtomicUsize>,
+}
+
+impl Engine {
+	//merge会把旧数据文件中的有效数据(即内存索引指向的数据)重写到merge目录下的新数据文件中
+	//新文件要等到下一次Engine::open时才会替换掉旧的数据文件,merge过程中数据库可以正常读写
+	pub fn merge(&self) -> Result<()> {
+		self.check_closed()?;
+		self.merge_context.merge()
+	}
+
+	//从hint文件中加载merge过的数据文件的索引,这些数据文件不需要再逐条读取
+	pub(crate) fn load_index_from_hint_file(&self) -> Result<()> {
+		let hint_file_name = self.options.dir_path.join(HINT_FILE_NAME);
+		if !hint_file_name.is_file() {
+			return Ok(());
+		}
+		let hint_file = DataFile::new_hint_file(&self.options.dir_path)?.with_key_provider(self.options.key_provider.clone());
+		let mut offset = 0;
+		loop {
+			let ReadLogRecord { record, size } = match hint_file.read_log_record(offset) {
+				Ok(result) => result,
+				Err(e) => {
+					if e == Errors::ReadDataFileEOF {
+						break;
+					}
+					return Err(e);
+				}
+			};
+			//merge之后才过期的数据不加载到索引中,其占用的空间可以在下一次merge时回收
+			if record.is_expired(now_millis()) {
+				let pos = match record.rec_type {
+					LogRecordType::BLOB_POINTER => decode_blob_hint(record.value)?.0,
+					_ => decode_log_record_pos(record.value)?,
+				};
+				self.add_reclaimable(pos);
+			} else {
+				let pos = match record.rec_type {
+					LogRecordType::BLOB_POINTER => {
+						let (pos, blob_pos) = decode_blob_hint(record.value)?;
+						self.blob_store.set_pointer(pos, blob_pos);
+						pos
+					}
+					//基础值有过期时间的操作数链表,接在key之前的位置后面
+					LogRecordType::MERGE_OPERAND => {
+						let pos = decode_log_record_pos(record.value)?;
+						let prev = self.indexer.get(record.key.clone());
+						if let Some(prev) = prev {
+							self.expirations.remove(prev);
+						}
+						self.operand_links.link(pos, prev);
+						pos
+					}
+					_ => decode_log_record_pos(record.value)?,
+				};
+				self.expirations.set(pos, record.expire_at);
+				self.indexer.put(record.key, pos);
+			}
+			offset += size;
+		}
+		Ok(())
+	}
+}
+
+impl MergeContext {
+	#[allow(clippy::too_many_arguments)]
+	pub(crate) fn new(
+		options: Arc<Options>,
+		active_file: Arc<RwLock<DataFile>>,
+		older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
+		indexer: Arc<dyn Indexer>,
+		batch_commit_lock: Arc<RwLock<()>>,
+		reclaimable_sizes: Arc<RwLock<HashMap<u32, u64>>>,
+		operand_links: Arc<OperandLinks>,
+		blob_store: Arc<BlobStore>,
+	) -> MergeContext {
+		MergeContext {
+			options,
+			active_file,
+			older_files,
+			indexer,
+			batch_commit_lock,
+			reclaimable_sizes,
+			operand_links,
+			blob_store,
+			merging_lock: Arc::new(Mutex::new(())),
+			merged_file_id: Arc::new(AtomicU32::new(0)),
+			spilling_batches: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	fn merge(&self) -> Result<()> {
+		//同一时刻只能有一个merge
+		let lock = self.merging_lock.try_lock();
+		if lock.is_none() {
+			return Err(Errors::MergeInProgress);
+		}
+		//数据库为空,不需要merge
+		if self.older_files.read().is_empty() && self.active_file.read().get_write_off() == 0 {
+			return Ok(());
+		}
+		//有没提交的spill数据时不能merge,不要删掉之前merge完成但还没有被替换的文件
+		if self.spilling_batches.load(Ordering::SeqCst) > 0 {
+			return Err(Errors::SpilledBatchInProgress);
+		}
+		let merge_path = get_merge_path(&self.options.dir_path);
+		//之前merge留下的目录,无论是否完成都删掉,这次merge会处理所有的旧文件
+		if merge_path.is_dir() {
+			remove_merge_dir(&merge_path)?;
+		}
+		//比non_merge_fid小的文件都参与了merge
+		let (merge_files, non_merge_fid) = self.rotate_merge_files()?;
+		//merge的结果先写到一个临时的数据库实例里面
+		let mut merge_opts = (*self.options).clone();
+		merge_opts.dir_path = merge_path.clone();
+		merge_opts.sync_writes = false;
+		merge_opts.auto_merge = false;
+		let merge_db = Engine::open(merge_opts)?;
+		//同时写一份hint文件,打开数据库时直接用它构建merge过的数据的索引
+		let hint_file = DataFile::new_hint_file(&merge_path)?.with_key_provider(self.options.key_provider.clone());
+		for data_file in merge_files.iter() {
+			let mut offset = 0;
+			loop {
+				let ReadLogRecord {
+					record: mut log_record,
+					size,
+				} = match data_file.read_log_record(offset) {
+					Ok(result) => result,
+					Err(Errors::ReadDataFileEOF) => break,
+					//打开数据库时已经跳过了损坏的数据,其中没有有效的记录
+					Err(e) if is_corrupted_record_err(&e) && self.options.recovery_mode == RecoveryMode::SkipCorrupted => {
+						match data_file.next_valid_record_offset(offset) {
+							Some(next_offset) => {
+								offset = next_offset;
+								continue;
+							}
+							None => break,
+						}
+					}
+					Err(e) => return Err(e),
+				};
+				let (real_key, _) = parse_log_record_key(&log_record.key)?;
+				//只有内存索引指向的位置正好是这条记录时,这条记录才是有效的
+				//被覆盖的数据,墓碑值和事务完成标识都不会被写入
+				let merge_pos = self.indexer.get(real_key.clone()).and_then(|pos| self.merged_pos(pos, non_merge_fid));
+				if let Some(pos) = merge_pos {
+					let now = now_millis();
+					//已经过期的数据直接丢弃
+					if pos.file_id == data_file.get_file_id() && pos.offset == offset && !log_record.is_expired(now) {
+						//操作数和之前的数据合并成一条普通的数据,之后操作数链表就不再需要了
+						if log_record.rec_type == LogRecordType::MERGE_OPERAND {
+							let operator = self.options.merge_operator.as_deref();
+							let read =
+								|pos| self.blob_store.materialize(read_log_record_at(&self.active_file, &self.older_files, pos)?, now);
+							let value = match resolve_operands(operator, &self.operand_links, pos, log_record, now, read)? {
+								Some((value, 0)) => value,
+								//基础值过期之后合并的结果会变化,原样保留整条链表
+								Some(_) => {
+									self.copy_operand_chain(&merge_db, &hint_file, &real_key, pos)?;
+									offset += size;
+									continue;
+								}
+								//merge过程中key被覆盖了,这条记录已经失效
+								None => {
+									offset += size;
+									continue;
+								}
+							};
+							log_record = LogRecord {
+								key: Vec::new(),
+								value,
+								rec_type: LogRecordType::NORMAL,
+								expire_at: 0,
+							};
+						}
+						//有效的事务数据都已经提交了,重写时去掉其seq_no
+						log_record.key = log_record_key_with_seq(real_key.clone(), NON_TRANSACTION_SEQ_NO);
+						let log_record_pos = merge_db.append_log_record(&mut log_record)?;
+						//blob文件不参与merge,只重写其中value的位置
+						let blob_pos = match log_record.rec_type {
+							LogRecordType::BLOB_POINTER => Some(decode_log_record_pos(log_record.value.clone())?),
+							_ => None,
+						};
+						hint_file.write_hint_record(real_key, log_record_pos, log_record.expire_at, blob_pos)?;
+					}
+				}
+				offset += size;
+			}
+		}
+		merge_db.sync()?;
+		hint_file.sync()?;
+		//merge产生的数据文件id是从0开始连续的
+		let merged_file_num = merge_db.active_file.read().get_file_id() + 1;
+		drop(merge_db);
+		//替换时id大于等于non_merge_fid的文件会被覆盖掉,这些文件里面是merge之后写入的数据
+		if merged_file_num > non_merge_fid {
+			warn!("merged {} data files, more than the {} reserved file ids", merged_file_num, non_merge_fid);
+			remove_merge_dir(&merge_path)?;
+			return Err(Errors::TooManyMergedFiles);
+		}
+		//最后写入merge完成的标识,没有这个文件的merge目录在打开数据库时会被直接删除
+		let mut value = BytesMut::new();
+		value.put_u32(non_merge_fid);
+		value.put_u32(merged_file_num);
+		let merge_fin_record = LogRecord {
+			key: MERGE_FIN_KEY.to_vec(),
+			value: value.to_vec(),
+			rec_type: LogRecordType::NORMAL,
+			expire_at: 0,
+		};
+		let merge_fin_file = DataFile::new_merge_fin_file(&merge_path)?;
+		merge_fin_file.write(&merge_fin_record.encode())?;
+		merge_fin_file.sync()?;
+		self.merged_file_id.store(non_merge_fid, Ordering::SeqCst);
+		Ok(())
+	}
+
+	//把pos往前的操作数和基础值按写入的顺序写入merge的数据文件,hint文件中也按这个顺序记录
+	//加载hint文件时按顺序重建操作数链表,基础值在加载时已经过期就从空值开始合并
+	fn copy_operand_chain(&self, merge_db: &Engine, hint_file: &DataFile, key: &[u8], pos: LogRecordPos) -> Result<()> {
+		let mut records = vec![];
+		let mut next = Some(pos);
+		while let Some(record_pos) = next {
+			let record = read_log_record_at(&self.active_file, &self.older_files, record_pos)?;
+			let is_operand = record.rec_type == LogRecordType::MERGE_OPERAND;
+			records.push(record);
+			next = match is_operand {
+				true => self.operand_links.prev(record_pos),
+				false => None,
+			};
+		}
+		for mut record in records.into_iter().rev() {
+			record.key = log_record_key_with_seq(key.to_vec(), NON_TRANSACTION_SEQ_NO);
+			let record_pos = merge_db.append_log_record(&mut record)?;
+			match record.rec_type {
+				LogRecordType::MERGE_OPERAND => hint_file.write_operand_hint_record(key.to_vec(), record_pos)?,
+				LogRecordType::BLOB_POINTER => {
+					let blob_pos = decode_log_record_pos(record.value)?;
+					hint_file.write_hint_record(key.to_vec(), record_pos, record.expire_at, Some(blob_pos))?;
+				}
+				_ => hint_file.write_hint_record(key.to_vec(), record_pos, record.expire_at, None)?,
+			}
+		}
+		Ok(())
+	}
+
+	//key在参与merge的文件中最新的位置
+	//merge开始之后写入的操作数还要用到之前的数据,沿着操作数链表找到参与merge的文件中的那一条
+	fn merged_pos(&self, mut pos: LogRecordPos, non_merge_fid: u32) -> Option<LogRecordPos> {
+		while pos.file_id >= non_merge_fid {
+			pos = self.operand_links.prev(pos)?;
+		}
+		Some(pos)
+	}
+
+	//把当前活跃文件变为旧文件,返回所有需要merge的旧文件(按id升序)和新的活跃文件的id
+	//之后的写入都会进入新的活跃文件,不参与这次merge
+	fn rotate_merge_files(&self) -> Result<(Vec<DataFile>, u32)> {
+		//等待正在提交的批量写入完成,防止一个批次的数据一半参与merge而一半没有
+		let _commit_lock = self.batch_commit_lock.write();
+		//加锁顺序和append_log_record保持一致,先活跃文件再旧文件
+		let mut active_file = self.active_file.write();
+		//拿到活跃文件的锁之后再检查,之后开始spill的batch只会写入新的活跃文件
+		if self.spilling_batches.load(Ordering::SeqCst) > 0 {
+			return Err(Errors::SpilledBatchInProgress);
+		}
+		let mut older_files = self.older_files.write();
+		let mut merge_file_ids: Vec<u32> = older_files.keys().copied().collect();
+
+		active_file.sync()?;
+		let active_file_id = active_file.get_file_id();
+		merge_file_ids.push(active_file_id);
+		merge_file_ids.sort_unstable();
+		//merge产生的文件id从0开始,新的活跃文件的id要比它们都大
+		//merge之后记录的大小可能会变化,例如开启了压缩或者加密,这里预留参与merge的文件数量两倍的id
+		let non_merge_fid = (active_file_id + 1).max(merge_file_ids.len() as u32 * 2);
+		*active_file = self.open_data_file(non_merge_fid)?;
+		older_files.insert(active_file_id, self.open_data_file(active_file_id)?);
+
+		//重新打开一份,merge读取时不需要持有older_files的锁
+		let mut merge_files = Vec::with_capacity(merge_file_ids.len());
+		for file_id in merge_file_ids {
+			merge_files.push(self.open_data_file(file_id)?);
+		}
+		Ok((merge_files, non_merge_fid))
+	}
+
+	fn open_data_file(&self, file_id: u32) -> Result<DataFile> {
+		Ok(DataFile::new(&self.options.dir_path, file_id)?.with_key_provider(self.options.key_provider.clone()))
+	}
+
+	//可回收的空间占数据文件总大小的比例,已经merge过但还没有被替换的文件不统计
+	fn reclaimable_ratio(&self) -> f32 {
+		let merged_file_id = self.merged_file_id.load(Ordering::SeqCst);
+		let mut total_size = 0;
+		{
+			let active_file = self.active_file.read();
+			let older_files = self.older_files.read();
+			if active_file.get_file_id() >= merged_file_id {
+				total_size += active_file.file_size();
+			}
+			for (file_id, data_file) in older_files.iter() {
+				if *file_id >= merged_file_id {
+					total_size += data_file.file_size();
+				}
+			}
+		}
+		if total_size == 0 {
+			return 0.0;
+		}
+		let reclaimable_size: u64 = self
+			.reclaimable_sizes
+			.read()
+			.iter()
+			.filter(|(file_id, _)| **file_id >= merged_file_id)
+			.map(|(_, size)| *size)
+			.sum();
+		reclaimable_size as f32 / total_size as f32
+	}
+}
+
+//后台merge线程,定期检查可回收空间的比例,超过阈值时进行merge
+pub(crate) struct MergeWorker {
+	stop_sender: Sender<()>,
+	handle: JoinHandle<()>,
+}
+
+impl MergeWorker {
+	pub(crate) fn start(ctx: MergeContext) -> MergeWorker {
+		let (stop_sender, stop_receiver) = mpsc::channel();
+		let interval = ctx.options.merge_check_interval;
+		let threshold = ctx.options.merge_ratio_threshold;
+		let handle = thread::spawn(move || loop {
+			//收到停止信号,或者Engine已经不存在了
+			if stop_receiver.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+				break;
+			}
+			//已经完成的merge要等到重启时才会替换旧文件,这里只统计之后写入的文件
+			//这些文件中可回收的空间再次达到阈值时重新merge,会删掉之前的结果把所有的旧文件重新merge一次
+			let ratio = ctx.reclaimable_ratio();
+			if ratio < threshold {
+				continue;
+			}
+			info!("reclaimable ratio {:.2} reaches threshold, start merging", ratio);
+			match ctx.merge() {
+				Ok(()) | Err(Errors::MergeInProgress) | Err(Errors::SpilledBatchInProgress) => {}
+				Err(e) => error!("background merge failed: {}", e),
+			}
+		});
+		MergeWorker {
+			stop_sender,
+			handle,
+		}
+	}
+
+	//通知后台线程退出并等待其结束,正在进行的merge会执行完
+	pub(crate) fn stop(self) {
+		let _ = self.stop_sender.send(());
+		if self.handle.join().is_err() {
+			error!("background merge thread panicked");
+		}
+	}
+}
+
+//merge目录和数据目录同级,名字为数据目录名-merge
+fn get_merge_path(dir_path: &Path) -> PathBuf {
+	let file_name = dir_path.file_name().unwrap_or_default().to_string_lossy();
+	let merge_name = format!("{}-{}", file_name, MERGE_DIR_NAME);
+	match dir_path.parent() {
+		Some(parent) => parent.join(merge_name),
+		None => PathBuf::from(merge_name),
+	}
+}
+
+fn remove_merge_dir(merge_path: &Path) -> Result<()> {
+	if let Err(e) = fs::remove_dir_all(merge_path) {
+		error!("failed to remove merge directory: {}", e);
+		return Err(Errors::FailedToApplyMergeFiles);
+	}
+	Ok(())
+}
+
+//打开数据库时调用,如果有已经完成的merge,用merge产生的数据文件替换掉旧的数据文件
+//替换过程中崩溃,下次打开时会重新执行一遍,每一步都是可以重复执行的
+pub(crate) fn load_merge_files(dir_path: &Path) -> Result<()> {
+	let merge_path = get_merge_path(dir_path);
+	if !merge_path.is_dir() {
+		return Ok(());
+	}
+	//merge没有完成,其结果不可用
+	if !merge_path.join(MERGE_FINISHED_FILE_NAME).is_file() {
+		warn!("found unfinished merge directory, removing it");
+		return remove_merge_dir(&merge_path);
+	}
+	let (non_merge_fid, merged_file_num) = read_merge_fin_file(&merge_path)?;
+	//先删除id不会被merge文件覆盖的旧文件
+	for file_id in merged_file_num..non_merge_fid {
+		let file_name = get_data_file_name(dir_path, file_id);
+		if file_name.is_file() {
+			if let Err(e) = fs::remove_file(file_name) {
+				error!("failed to remove merged data file: {}", e);
+				return Err(Errors::FailedToApplyMergeFiles);
+			}
+		}
+	}
+	//再把merge文件移动过来,rename会直接覆盖id相同的旧文件
+	for file_id in 0..merged_file_num {
+		move_merge_file(&get_data_file_name(&merge_path, file_id), &get_data_file_name(dir_path, file_id))?;
+	}
+	//hint文件和merge完成的标识也要移动过来,打开数据库时用来判断哪些文件的索引可以从hint文件中加载
+	//merge完成的标识最后移动,在此之前崩溃都会重新执行一遍
+	move_merge_file(&merge_path.join(HINT_FILE_NAME), &dir_path.join(HINT_FILE_NAME))?;
+	move_merge_file(&merge_path.join(MERGE_FINISHED_FILE_NAME), &dir_path.join(MERGE_FINISHED_FILE_NAME))?;
+	remove_merge_dir(&merge_path)
+}
+
+fn move_merge_file(src: &Path, dst: &Path) -> Result<()> {
+	//上一次打开时可能已经移动过了
+	if !src.is_file() {
+		return Ok(());
+	}
+	if let Err(e) = fs::rename(src, dst) {
+		error!("failed to move merged file: {}", e);
+		return Err(Errors::FailedToApplyMergeFiles);
+	}
+	Ok(())
+}
+
+//读取merge完成的标识,返回没有参与merge的最小文件id和merge产生的数据文件数量
+fn read_merge_fin_file(dir_path: &Path) -> Result<(u32, u32)> {
+	let merge_fin_file = DataFile::new_merge_fin_file(dir_path)?;
+	let merge_fin_record = merge_fin_file.read_log_record(0)?.record;
+	let mut value = merge_fin_record.value.as_slice();
+	if value.len() != 8 {
+		return Err(Errors::DataDirectoryCorrupted);
+	}
+	let non_merge_fid = value.get_u32();
+	let merged_file_num = value.get_u32();
+	if merged_file_num > non_merge_fid {
+		return Err(Errors::DataDirectoryCorrupted);
+	}
+	Ok((non_merge_fid, merged_file_num))
+}
+
+//数据目录中id比返回值小的数据文件都是merge产生的,其索引在hint文件中
+pub(crate) fn get_non_merge_file_id(dir_path: &Path) -> Result<u32> {
+	if !dir_path.join(MERGE_FINISHED_FILE_NAME).is_file() {
+		return Ok(0);
+	}
+	Ok(read_merge_fin_file(dir_path)?.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use std::time::{Duration, Instant};
+
+	use bytes::Bytes;
+
+	use crate::encryption::KeyRing;
+	use crate::options::Options;
+	use crate::util::rand_kv::{get_test_key, get_test_value};
+
+	use super::*;
+
+	//数据目录下所有数据文件的总大小
+	fn data_dir_size(dir_path: &Path) -> u64 {
+		fs::read_dir(dir_path)
+			.unwrap()
+			.map(|entry| entry.unwrap().metadata().unwrap().len())
+			.sum()
+	}
+
+	#[test]
+	fn test_merge_empty() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-1"),
+			data_file_size: 32 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(engine.merge().is_ok());
+		assert!(!get_merge_path(&opts.dir_path).exists());
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_all_valid() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-2"),
+			data_file_size: 32 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..50000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		assert!(engine.merge().is_ok());
+		std::mem::drop(engine);
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(50000, engine2.list_keys().len());
+		for i in 0..50000 {
+			assert_eq!(get_test_value(i), engine2.get(get_test_key(i)).unwrap());
+		}
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_stale_data() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-3"),
+			data_file_size: 4 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..50000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		for i in 0..10000 {
+			engine.delete(get_test_key(i)).expect("failed to delete");
+		}
+		for i in 40000..50000 {
+			engine.put(get_test_key(i), Bytes::from("new value")).expect("failed to put");
+		}
+		let size_before = data_dir_size(&opts.dir_path);
+		assert!(engine.merge().is_ok());
+		std::mem::drop(engine);
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(data_dir_size(&opts.dir_path) < size_before);
+		assert!(!get_merge_path(&opts.dir_path).exists());
+		assert_eq!(40000, engine2.list_keys().len());
+		for i in 0..10000 {
+			assert_eq!(Errors::KeyNotFound, engine2.get(get_test_key(i)).err().unwrap());
+		}
+		for i in 10000..40000 {
+			assert_eq!(get_test_value(i), engine2.get(get_test_key(i)).unwrap());
+		}
+		for i in 40000..50000 {
+			assert_eq!(Bytes::from("new value"), engine2.get(get_test_key(i)).unwrap());
+		}
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_with_writes_after_merge() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-4"),
+			data_file_size: 4 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..20000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		assert!(engine.merge().is_ok());
+		//merge之后写入的数据在新的活跃文件里面,不会被merge文件覆盖
+		for i in 0..5000 {
+			engine.put(get_test_key(i), Bytes::from("new value")).expect("failed to put");
+		}
+		for i in 20000..25000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		std::mem::drop(engine);
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(25000, engine2.list_keys().len());
+		for i in 0..5000 {
+			assert_eq!(Bytes::from("new value"), engine2.get(get_test_key(i)).unwrap());
+		}
+		for i in 5000..25000 {
+			assert_eq!(get_test_value(i), engine2.get(get_test_key(i)).unwrap());
+		}
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_unfinished_is_discarded() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-5"),
+			data_file_size: 4 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..10000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		assert!(engine.merge().is_ok());
+		std::mem::drop(engine);
+		//模拟merge过程中崩溃,merge完成的标识还没有写入
+		let merge_path = get_merge_path(&opts.dir_path);
+		fs::remove_file(merge_path.join(MERGE_FINISHED_FILE_NAME)).unwrap();
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(!merge_path.exists());
+		assert_eq!(10000, engine2.list_keys().len());
+		for i in 0..10000 {
+			assert_eq!(get_test_value(i), engine2.get(get_test_key(i)).unwrap());
+		}
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_load_index_from_hint_file() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-6"),
+			data_file_size: 4 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..30000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		assert!(engine.merge().is_ok());
+		for i in 0..10000 {
+			engine.put(get_test_key(i), Bytes::from("new value")).expect("failed to put");
+		}
+		std::mem::drop(engine);
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(opts.dir_path.join(HINT_FILE_NAME).is_file());
+		assert!(opts.dir_path.join(MERGE_FINISHED_FILE_NAME).is_file());
+		let non_merge_fid = get_non_merge_file_id(&opts.dir_path).unwrap();
+		assert!(non_merge_fid > 0);
+		assert_eq!(30000, engine2.list_keys().len());
+		for i in 0..10000 {
+			assert_eq!(Bytes::from("new value"), engine2.get(get_test_key(i)).unwrap());
+		}
+		for i in 10000..30000 {
+			assert_eq!(get_test_value(i), engine2.get(get_test_key(i)).unwrap());
+		}
+
+		//第二次merge会覆盖掉上一次的hint文件
+		assert!(engine2.merge().is_ok());
+		std::mem::drop(engine2);
+		let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(get_non_merge_file_id(&opts.dir_path).unwrap() > non_merge_fid);
+		assert_eq!(30000, engine3.list_keys().len());
+		for i in 0..10000 {
+			assert_eq!(Bytes::from("new value"), engine3.get(get_test_key(i)).unwrap());
+		}
+		for i in 10000..30000 {
+			assert_eq!(get_test_value(i), engine3.get(get_test_key(i)).unwrap());
+		}
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_files_larger_than_before() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-11"),
+			data_file_size: 256 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..10000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+
+		//开启加密之后每条记录都变大了,merge产生的文件比参与merge的文件多
+		let opts = Options {
+			key_provider: Some(Arc::new(KeyRing::new(1, [1; 32]))),
+			..opts
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let file_num = engine.stat().unwrap().data_file_num;
+		assert!(engine.merge().is_ok());
+		engine.put(get_test_key(0), Bytes::from("new value")).expect("failed to put");
+		std::mem::drop(engine);
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(get_non_merge_file_id(&opts.dir_path).unwrap() as usize > file_num);
+		assert_eq!(10000, engine2.list_keys().len());
+		assert_eq!(Bytes::from("new value"), engine2.get(get_test_key(0)).unwrap());
+		for i in 1..10000 {
+			assert_eq!(get_test_value(i), engine2.get(get_test_key(i)).unwrap());
+		}
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_reclaimable_ratio() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-7"),
+			data_file_size: 4 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..10000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		assert_eq!(0.0, engine.merge_context.reclaimable_ratio());
+		//覆盖所有的数据,一半的空间是可以回收的
+		for i in 0..10000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		let ratio = engine.merge_context.reclaimable_ratio();
+		assert!(ratio > 0.49 && ratio < 0.51);
+		//merge过的文件不再统计
+		assert!(engine.merge().is_ok());
+		assert_eq!(0.0, engine.merge_context.reclaimable_ratio());
+		std::mem::drop(engine);
+
+		//重启后merge过的文件都是有效数据
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(0.0, engine2.merge_context.reclaimable_ratio());
+		for i in 0..5000 {
+			engine2.delete(get_test_key(i)).expect("failed to delete");
+		}
+		assert!(engine2.merge_context.reclaimable_ratio() > 0.0);
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	//在超时之前轮询,直到条件满足
+	fn wait_until(cond: impl Fn() -> bool) -> bool {
+		let deadline = Instant::now() + Duration::from_secs(10);
+		while Instant::now() < deadline {
+			if cond() {
+				return true;
+			}
+			std::thread::sleep(Duration::from_millis(10));
+		}
+		cond()
+	}
+
+	#[test]
+	fn test_auto_merge() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-8"),
+			data_file_size: 4 * 1024 * 1024,
+			auto_merge: true,
+			merge_ratio_threshold: 0.3,
+			merge_check_interval: Duration::from_millis(50),
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..10000 {
+			engine.put(get_test_key(i), get_test_value(i)).expect("failed to put");
+		}
+		for i in 0..10000 {
+			engine.put(get_test_key(i), Bytes::from("new value")).expect("failed to put");
+		}
+		//等待后台线程完成merge
+		let merged_file_id = || engine.merge_context.merged_file_id.load(Ordering::SeqCst);
+		assert!(wait_until(|| merged_file_id() > 0));
+		let merge_fin_file = get_merge_path(&opts.dir_path).join(MERGE_FINISHED_FILE_NAME);
+		assert!(merge_fin_file.is_file());
+
+		//完成的merge还没有被替换时,merge之后写入的文件中可回收的空间再次超过阈值,会重新merge
+		let first_merged_file_id = merged_file_id();
+		for _ in 0..2 {
+			for i in 0..10000 {
+				engine.put(get_test_key(i), Bytes::from("newer value")).expect("failed to put");
+			}
+		}
+		assert!(wait_until(|| merged_file_id() > first_merged_file_id));
+		assert!(merge_fin_file.is_file());
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+
+		let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(10000, engine2.list_keys().len());
+		for i in 0..10000 {
+			assert_eq!(Bytes::from("newer value"), engine2.get(get_test_key(i)).unwrap());
+		}
+		engine2.close().expect("failed to close");
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_invalid_merge_ratio() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-9"),
+			merge_ratio_threshold: 0.0,
+			..Default::default()
+		};
+		assert_eq!(Errors::InvalidMergeRatio, Engine::open(opts).err().unwrap());
+	}
+
+	#[test]
+	fn test_invalid_merge_check_interval() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-merge-10"),
+			auto_merge: true,
+			merge_check_interval: Duration::ZERO,
+			..Default::default()
+		};
+		assert_eq!(Errors::InvalidMergeCheckInterval, Engine::open(opts).err().unwrap());
+	}
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::batch::parse_log_record_key;
+use crate::data::log_record::{LogRecord, LogRecordPos, LogRecordType};
+use crate::errors::{Errors, Result};
+
+//读取时操作数链表超过这个长度,就把合并之后的值重写成一条普通的数据
+pub(crate) const MAX_OPERAND_CHAIN_LEN: usize = 64;
+
+//合并操作符,把操作数合并到已有的值上面,例如计数器的加法,列表的追加
+//Engine::merge_value只追加一条操作数记录,读取时才用合并操作符计算出最终的值
+pub trait MergeOperator: Send + Sync {
+	//existing为None说明key之前不存在
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+//一条操作数记录在链表中的信息
+#[derive(Clone, Copy)]
+struct Link {
+	//写入这条操作数之前key所在的位置
+	prev: Option<LogRecordPos>,
+	//从这条操作数往前连续的操作数的数量,包括它自己
+	len: usize,
+}
+
+//操作数记录之间的链表,从最新的操作数往前可以找到key的基础值
+//链表只在内存中维护,打开数据库时按写入的顺序重建,merge时会把整条链表合并成一条普通的数据
+#[derive(Default)]
+pub(crate) struct OperandLinks {
+	//操作数记录的(file_id, offset) -> 链表信息
+	links: RwLock<HashMap<(u32, u64), Link>>,
+	//有快照时被覆盖的链表,快照可能还会读取,等快照都释放之后再删除
+	retired: Mutex<Vec<LogRecordPos>>,
+}
+
+impl OperandLinks {
+	pub(crate) fn link(&self, pos: LogRecordPos, prev: Option<LogRecordPos>) {
+		let mut links = self.links.write();
+		let len = prev.and_then(|prev| links.get(&(prev.file_id, prev.offset))).map_or(1, |link| link.len + 1);
+		links.insert((pos.file_id, pos.offset), Link { prev, len });
+	}
+
+	//pos之前的一条记录,pos不是操作数或者之前key不存在时返回None
+	pub(crate) fn prev(&self, pos: LogRecordPos) -> Option<LogRecordPos> {
+		self.get(pos).and_then(|link| link.prev)
+	}
+
+	//从pos往前连续的操作数的数量,pos不是操作数时返回0
+	pub(crate) fn len(&self, pos: LogRecordPos) -> usize {
+		self.get(pos).map_or(0, |link| link.len)
+	}
+
+	pub(crate) fn is_empty(&self) -> bool {
+		self.links.read().is_empty()
+	}
+
+	fn get(&self, pos: LogRecordPos) -> Option<Link> {
+		self.links.read().get(&(pos.file_id, pos.offset)).copied()
+	}
+
+	//删除从pos开始的整条链表,调用方要保证没有快照还会读取这条链表
+	pub(crate) fn unlink(&self, pos: LogRecordPos) {
+		//大部分被覆盖的数据都不是操作数,不需要拿写锁
+		if self.get(pos).is_none() {
+			return;
+		}
+		let mut links = self.links.write();
+		let mut next = Some(pos);
+		while let Some(pos) = next {
+			next = links.remove(&(pos.file_id, pos.offset)).and_then(|link| link.prev);
+		}
+	}
+
+	//有快照时先记下被覆盖的链表,在unlink_retired时再删除
+	pub(crate) fn retire(&self, pos: LogRecordPos) {
+		if self.get(pos).is_some() {
+			self.retired.lock().push(pos);
+		}
+	}
+
+	//所有的快照都释放之后调用,删除之前记下的链表
+	pub(crate) fn unlink_retired(&self) {
+		let retired = std::mem::take(&mut *self.retired.lock());
+		for pos in retired {
+			self.unlink(pos);
+		}
+	}
+}
+
+//record是pos处的操作数记录,往前读出所有的操作数和基础值,再按写入的顺序依次合并
+//基础值按now判断是否过期,读取过程中key被覆盖,链表已经被删除时返回None
+//返回合并之后的值和基础值的过期时间,基础值不存在或者永不过期时为0
+pub(crate) fn resolve_operands<F>(
+	operator: Option<&dyn MergeOperator>,
+	links: &OperandLinks,
+	pos: LogRecordPos,
+	record: LogRecord,
+	now: u64,
+	read: F,
+) -> Result<Option<(Vec<u8>, u64)>>
+where
+	F: Fn(LogRecordPos) -> Result<LogRecord>,
+{
+	let operator = operator.ok_or(Errors::MergeOperatorNotFound)?;
+	let (key, _) = parse_log_record_key(&record.key)?;
+	let mut operands = vec![record.value];
+	let mut base = None;
+	let mut base_expire_at = 0;
+	let Some(mut link) = links.get(pos) else {
+		return Ok(None);
+	};
+	//遇到普通的数据或者墓碑值就停止
+	while let Some(prev_pos) = link.prev {
+		let prev_record = read(prev_pos)?;
+		match prev_record.rec_type {
+			LogRecordType::MERGE_OPERAND => {
+				operands.push(prev_record.value);
+				link = match links.get(prev_pos) {
+					Some(link) => link,
+					None => return Ok(None),
+				};
+			}
+			//过期的数据和墓碑值一样,从空值开始合并
+			LogRecordType::NORMAL => {
+				if !prev_record.is_expired(now) {
+					base = Some(prev_record.value);
+					base_expire_at = prev_record.expire_at;
+				}
+				break;
+			}
+			_ => break,
+		}
+	}
+	let value = operands
+		.iter()
+		.rev()
+		.fold(base, |existing, operand| Some(operator.merge(&key, existing.as_deref(), operand)));
+	Ok(Some((value.unwrap_or_default(), base_expire_at)))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use std::sync::Arc;
+	use std::time::{Duration, Instant};
+
+	use bytes::Bytes;
+
+	use crate::db::Engine;
+	use crate::options::{IndexType, Options};
+
+	use super::*;
+
+	//u64计数器,操作数是要加上的值
+	struct CounterOperator;
+
+	impl MergeOperator for CounterOperator {
+		fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+			let parse = |v: &[u8]| u64::from_le_bytes(v.try_into().unwrap());
+			(existing.map_or(0, parse) + parse(operand)).to_le_bytes().to_vec()
+		}
+	}
+
+	fn counter(n: u64) -> Bytes {
+		Bytes::from(n.to_le_bytes().to_vec())
+	}
+
+	fn counter_options(dir: &str) -> Options {
+		Options {
+			dir_path: PathBuf::from(dir),
+			data_file_size: 64 * 1024 * 1024,
+			merge_operator: Some(Arc::new(CounterOperator)),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_merge_value() {
+		let opts = counter_options("/tmp/bitcask-rs-merge-operator-1");
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let key = Bytes::from("counter");
+
+		//key不存在时从空值开始合并
+		engine.merge_value(key.clone(), counter(1)).unwrap();
+		engine.merge_value(key.clone(), counter(2)).unwrap();
+		assert_eq!(counter(3), engine.get(key.clone()).unwrap());
+
+		//合并到put的值上面,删除之后重新开始
+		engine.put(key.clone(), counter(10)).unwrap();
+		engine.merge_value(key.clone(), counter(5)).unwrap();
+		assert_eq!(counter(15), engine.get(key.clone()).unwrap());
+		let snapshot = engine.snapshot();
+		engine.delete(key.clone()).unwrap();
+		engine.merge_value(key.clone(), counter(7)).unwrap();
+		assert_eq!(counter(7), engine.get(key.clone()).unwrap());
+		assert_eq!(counter(15), snapshot.get(key.clone()).unwrap());
+		drop(snapshot);
+		assert_eq!(Err(Errors::KeyIsEmpty), engine.merge_value(Bytes::new(), counter(1)));
+
+		//并发的merge_value不需要额外加锁
+		std::thread::scope(|s| {
+			for _ in 0..4 {
+				s.spawn(|| {
+					for _ in 0..100 {
+						engine.merge_value(key.clone(), counter(1)).unwrap();
+					}
+				});
+			}
+		});
+		assert_eq!(counter(407), engine.get(key.clone()).unwrap());
+
+		//重启之后重建操作数链表
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(counter(407), engine.get(key.clone()).unwrap());
+		let items: Vec<(Bytes, Bytes)> = engine
+			.iter(&Default::default())
+			.map(|item| item.expect("failed to read value"))
+			.collect();
+		assert_eq!(vec![(key.clone(), counter(407))], items);
+
+		//没有配置合并操作符时无法写入和读取操作数
+		engine.merge_value(key.clone(), counter(1)).unwrap();
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(Options {
+			merge_operator: None,
+			..opts.clone()
+		})
+		.expect("failed to open engine");
+		assert_eq!(Err(Errors::MergeOperatorNotFound), engine.merge_value(key.clone(), counter(1)));
+		assert_eq!(Err(Errors::MergeOperatorNotFound), engine.get(key.clone()));
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_operand_chain_released_and_collapsed() {
+		let opts = counter_options("/tmp/bitcask-rs-merge-operator-3");
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let key = Bytes::from("counter");
+		let n = MAX_OPERAND_CHAIN_LEN as u64 * 2;
+
+		//被覆盖的链表在没有快照时直接删除,有快照时等快照释放之后再删除
+		for _ in 0..n {
+			engine.merge_value(key.clone(), counter(1)).unwrap();
+		}
+		engine.put(key.clone(), counter(0)).unwrap();
+		assert!(engine.operand_links.is_empty());
+		engine.merge_value(key.clone(), counter(1)).unwrap();
+		let snapshot = engine.snapshot();
+		engine.delete(key.clone()).unwrap();
+		assert!(!engine.operand_links.is_empty());
+		assert_eq!(counter(1), snapshot.get(key.clone()).unwrap());
+		drop(snapshot);
+		assert!(engine.operand_links.is_empty());
+
+		//打开数据库时合并过长的链表
+		for _ in 0..n {
+			engine.merge_value(key.clone(), counter(1)).unwrap();
+		}
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert!(engine.operand_links.is_empty());
+		assert_eq!(counter(n), engine.get(key.clone()).unwrap());
+
+		//读取时合并过长的链表,合并不会产生新的版本
+		for _ in 0..n {
+			engine.merge_value(key.clone(), counter(1)).unwrap();
+		}
+		let seq_no = engine.snapshot().seq_no();
+		assert_eq!(counter(n * 2), engine.get(key.clone()).unwrap());
+		assert!(engine.operand_links.is_empty());
+		assert_eq!(seq_no, engine.snapshot().seq_no());
+		assert_eq!(counter(n * 2), engine.get(key.clone()).unwrap());
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_value_concurrently() {
+		let opts = Options {
+			index_type: IndexType::SkipList,
+			..counter_options("/tmp/bitcask-rs-merge-operator-5")
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+		//并发的单条写入的提交顺序和数据文件中的顺序一致,重启之后读到的值不变
+		std::thread::scope(|s| {
+			for i in 0..4 {
+				let engine = &engine;
+				s.spawn(move || {
+					for j in 0..2000 {
+						engine.merge_value(Bytes::from("a"), counter(1)).unwrap();
+						let key = Bytes::from(format!("b-{}", j % 16));
+						match (i + j) % 3 {
+							0 => engine.put(key, counter(i * 1000 + j)).unwrap(),
+							1 => engine.merge_value(key, counter(1)).unwrap(),
+							_ => engine.delete(key).unwrap(),
+						}
+					}
+				});
+			}
+		});
+		assert_eq!(counter(8000), engine.get(Bytes::from("a")).unwrap());
+		let values: Vec<_> = (0..16).map(|j| engine.get(Bytes::from(format!("b-{}", j)))).collect();
+
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(counter(8000), engine.get(Bytes::from("a")).unwrap());
+		for (j, value) in values.into_iter().enumerate() {
+			assert_eq!(value, engine.get(Bytes::from(format!("b-{}", j))));
+		}
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_operand_chain_with_ttl_base() {
+		let opts = counter_options("/tmp/bitcask-rs-merge-operator-4");
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let n = MAX_OPERAND_CHAIN_LEN as u64 * 2;
+		let ttl = Duration::from_secs(1);
+		let expire_at = Instant::now() + ttl;
+		engine.put_with_ttl(Bytes::from("a"), counter(10), ttl).unwrap();
+		engine.put_with_ttl(Bytes::from("b"), counter(10), ttl).unwrap();
+		for _ in 0..n {
+			engine.merge_value(Bytes::from("a"), counter(1)).unwrap();
+		}
+		for _ in 0..3 {
+			engine.merge_value(Bytes::from("b"), counter(1)).unwrap();
+		}
+
+		//基础值有过期时间,读取时不会合并过长的链表
+		assert_eq!(counter(10 + n), engine.get(Bytes::from("a")).unwrap());
+		let pos = engine.indexer.get(b"a".to_vec()).unwrap();
+		assert_eq!(n as usize, engine.operand_links.len(pos));
+
+		//merge时也原样保留链表
+		engine.merge().expect("failed to merge");
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(counter(10 + n), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(counter(13), engine.get(Bytes::from("b")).unwrap());
+
+		//基础值过期之后和没有合并过的链表一样,从空值开始合并
+		std::thread::sleep(expire_at.saturating_duration_since(Instant::now()) + Duration::from_millis(100));
+		assert_eq!(counter(n), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(counter(3), engine.get(Bytes::from("b")).unwrap());
+		engine.merge().expect("failed to merge");
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(counter(n), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(counter(3), engine.get(Bytes::from("b")).unwrap());
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_merge_value_with_compaction() {
+		let opts = counter_options("/tmp/bitcask-rs-merge-operator-2");
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		for i in 0..100 {
+			engine.merge_value(Bytes::from(format!("key-{}", i % 10)), counter(i)).unwrap();
+		}
+		engine.merge().expect("failed to merge");
+		//merge之后写入的操作数合并到merge之前的数据上面
+		engine.merge_value(Bytes::from("key-0"), counter(1000)).unwrap();
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		//merge时操作数已经合并成了普通的数据
+		assert!(engine.operand_links.prev(engine.indexer.get(b"key-1".to_vec()).unwrap()).is_none());
+		assert_eq!(counter(450 + 1000), engine.get(Bytes::from("key-0")).unwrap());
+		for i in 1..10u64 {
+			let expected = (0..10).map(|j| j * 10 + i).sum();
+			assert_eq!(counter(expected), engine.get(Bytes::from(format!("key-{}", i))).unwrap());
+		}
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+}
@@ -1,5 +1,10 @@
-use std::fmt::Debug;
 use std::path::PathBuf;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::encryption::KeyProvider;
+use crate::merge_operator::MergeOperator;
 
 //数据库启动时用户所进行的配置
 #[derive(Clone)]
@@ -11,8 +16,28 @@ pub struct Options {
     //由于page cache的存在,写文件会先写内存
     //每次写完文件是否持久化文件,如果这样做可以增加可靠性但是降低性能(可以优化为直接IO)
     pub sync_writes: bool,
-    //目前只支持BTree
+    //内存索引的类型,支持BTree和SkipList
     pub index_type: IndexType,
+    //是否开启后台merge
+    //merge产生的文件要等到下一次打开数据库时才会替换旧的数据文件,在此之前空间不会被回收
+    //之后写入的数据文件中可回收的空间再次达到阈值时,会重新merge所有的旧文件
+    pub auto_merge: bool,
+    //可回收的空间(被覆盖的数据,墓碑值等)占数据文件总大小的比例达到这个阈值时,后台进行merge
+    pub merge_ratio_threshold: f32,
+    //后台检查是否需要merge的时间间隔,不能为0
+    pub merge_check_interval: Duration,
+    //打开数据库时遇到损坏的记录如何处理
+    pub recovery_mode: RecoveryMode,
+    //Engine::merge_value使用的合并操作符,没有配置时不能调用merge_value
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    //value的大小达到这个值时单独存放在blob文件中,数据文件里只保存其位置,None表示不分离
+    pub blob_value_threshold: Option<usize>,
+    //blob文件中失效的数据占文件大小的比例达到这个阈值时,Engine::gc_blobs会重写这个文件
+    pub blob_gc_ratio: f32,
+    //写入时value使用的压缩算法,修改之后之前写入的数据仍然可以正常读取
+    pub compression: Compression,
+    //配置之后新写入的记录中key和value都会加密,读取加密的记录时根据记录中的密钥id查找密钥
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 #[derive(Clone, Copy)]
@@ -21,6 +46,26 @@ pub enum IndexType {
     SkipList,
 }
 
+//value的压缩算法,每条记录单独压缩,压缩过的记录和没有压缩的记录可以在同一个文件中
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Snappy,
+}
+
+//打开数据库时遇到损坏记录的处理方式,最新数据文件末尾没有写完的记录总是会被截断
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryMode {
+    //直接返回错误
+    Strict,
+    //跳过损坏的数据,从后面第一条完整的记录继续加载
+    SkipCorrupted,
+    //在第一条损坏的记录处停止加载,之后的数据全部丢弃
+    //丢弃的数据不会被删除,而是移动到数据目录下以.corrupt结尾的文件中,见RecoveryReport::quarantined_files
+    StopAtFirstCorruption,
+}
+
 //默认的选项
 impl Default for Options {
     fn default() -> Self {
@@ -29,6 +74,15 @@ impl Default for Options {
             data_file_size: 256 * 1024 * 1024, //256mb
             sync_writes: false,
             index_type: IndexType::BTree,
+            auto_merge: false,
+            merge_ratio_threshold: 0.5,
+            merge_check_interval: Duration::from_secs(60),
+            recovery_mode: RecoveryMode::Strict,
+            merge_operator: None,
+            blob_value_threshold: None,
+            blob_gc_ratio: 0.5,
+            compression: Compression::None,
+            key_provider: None,
         }
     }
 }
@@ -39,13 +93,18 @@ pub struct IteratorOptions {
     //prefix代表只找以prefix开头的key
     pub prefix: Vec<u8>,
     pub reverse: bool,
+    //迭代范围的下界和上界,可以是包含或者不包含,和prefix同时指定时取两者的交集
+    pub lower_bound: Bound<Vec<u8>>,
+    pub upper_bound: Bound<Vec<u8>>,
 }
 
 impl Default for IteratorOptions {
     fn default() -> Self {
         Self {
-            prefix: vec![],
+            prefix: Default::default(),
             reverse: false,
+            lower_bound: Bound::Unbounded,
+            upper_bound: Bound::Unbounded,
         }
     }
 }
@@ -55,6 +114,9 @@ pub struct WriteBatchOptions {
     pub max_batch_num: usize,
     //提交的时候是否进行持久化
     pub sync_writes: bool,
+    //写入时直接把数据追加到数据文件中,提交时只写入事务完成的标识
+    //批次的大小不再受max_batch_num和内存的限制,但是不支持回滚到保存点,batch提交之前不能进行merge
+    pub spill_to_disk: bool,
 }
 
 impl Default for WriteBatchOptions {
@@ -62,6 +124,7 @@ impl Default for WriteBatchOptions {
         Self {
             max_batch_num: 10000,
             sync_writes: true,
+            spill_to_disk: false,
         }
     }
 }
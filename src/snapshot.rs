@@ -0,0 +1,384 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic;
+
+use bytes::Bytes;
+use parking_lot::{Mutex, RwLock};
+
+use crate::data::log_record::LogRecordPos;
+use crate::db::Engine;
+use crate::errors::{Errors, Result};
+use crate::index::{IndexIterator, Indexer, IterCursor};
+use crate::options::IteratorOptions;
+use crate::ttl::now_millis;
+
+//key -> [(修改这个key的seq_no, 修改之前的位置)],按seq_no递增,位置为None说明修改之前key不存在
+type Versions = BTreeMap<Vec<u8>, Vec<(usize, Option<LogRecordPos>)>>;
+
+//快照存在期间被覆盖或者删除的数据的旧版本
+//内存索引只保存每个key最新的位置,快照读取时先查索引,再用这里的旧版本把数据还原到快照时的状态
+#[derive(Default)]
+pub(crate) struct History {
+	//活跃的快照的seq_no及其数量
+	snapshots: Mutex<BTreeMap<usize, usize>>,
+	versions: RwLock<Versions>,
+}
+
+impl History {
+	//在更新内存索引之前调用,调用方需要持有batch_commit_lock
+	pub(crate) fn record(&self, indexer: &dyn Indexer, key: &[u8], seq_no: usize) {
+		if self.snapshots.lock().is_empty() {
+			return;
+		}
+		let old_pos = indexer.get(key.to_vec());
+		self.versions.write().entry(key.to_vec()).or_default().push((seq_no, old_pos));
+	}
+
+	//key在seq_no时的位置,返回None说明之后没有修改过,以内存索引为准
+	fn get(&self, key: &[u8], seq_no: usize) -> Option<Option<LogRecordPos>> {
+		let versions = self.versions.read();
+		let key_versions = versions.get(key)?;
+		key_versions.iter().find(|(modified_at, _)| *modified_at > seq_no).map(|(_, pos)| *pos)
+	}
+
+	//key在seq_no之后有没有被修改过,需要有seq_no对应的快照存在
+	pub(crate) fn modified_after(&self, key: &[u8], seq_no: usize) -> bool {
+		self.get(key, seq_no).is_some()
+	}
+
+	//有没有活跃的快照,调用方需要持有batch_commit_lock才能保证之后不会创建新的快照
+	pub(crate) fn has_snapshots(&self) -> bool {
+		!self.snapshots.lock().is_empty()
+	}
+
+	fn acquire(&self, seq_no: usize) {
+		*self.snapshots.lock().entry(seq_no).or_insert(0) += 1;
+	}
+
+	//快照释放后,比所有活跃快照都旧的版本不会再被读到
+	fn release(&self, seq_no: usize) {
+		let mut snapshots = self.snapshots.lock();
+		if let Some(count) = snapshots.get_mut(&seq_no) {
+			*count -= 1;
+			if *count == 0 {
+				snapshots.remove(&seq_no);
+			}
+		}
+		let mut versions = self.versions.write();
+		match snapshots.keys().next() {
+			Some(oldest) => {
+				versions.retain(|_, key_versions| {
+					key_versions.retain(|(modified_at, _)| *modified_at > *oldest);
+					!key_versions.is_empty()
+				});
+			}
+			None => versions.clear(),
+		}
+	}
+
+	//查找满足边界的第一个key
+	fn lookup(&self, bound: Bound<&[u8]>, ascending: bool) -> Option<(Vec<u8>, ())> {
+		let versions = self.versions.read();
+		let item = match ascending {
+			true => versions.range::<[u8], _>((bound, Bound::Unbounded)).next(),
+			false => versions.range::<[u8], _>((Bound::Unbounded, bound)).next_back(),
+		};
+		item.map(|(key, _)| (key.clone(), ()))
+	}
+}
+
+//数据库在某个seq_no时的只读视图,之后的写入对快照不可见
+//快照只存在于内存中,数据库关闭后就失效了
+pub struct Snapshot<'a> {
+	engine: &'a Engine,
+	seq_no: usize,
+	//创建快照的时间,快照中的数据按这个时间判断是否过期
+	now: u64,
+}
+
+impl Engine {
+	//获取当前数据库的快照
+	pub fn snapshot(&self) -> Snapshot<'_> {
+		//和提交互斥,快照不会看到提交了一半的批量写入
+		let _lock = self.batch_commit_lock.write();
+		let seq_no = self.seq_no.load(atomic::Ordering::SeqCst);
+		self.history.acquire(seq_no);
+		Snapshot {
+			engine: self,
+			seq_no,
+			now: now_millis(),
+		}
+	}
+}
+
+impl Snapshot<'_> {
+	pub fn seq_no(&self) -> usize {
+		self.seq_no
+	}
+
+	pub fn get(&self, key: Bytes) -> Result<Bytes> {
+		if key.is_empty() {
+			return Err(Errors::KeyIsEmpty);
+		}
+		match self.get_pos(&key) {
+			Some(pos) => self.engine.get_value_at(pos, self.now),
+			None => Err(Errors::KeyNotFound),
+		}
+	}
+
+	//获取快照的迭代器
+	pub fn iter(&self, iterator_options: &IteratorOptions) -> SnapshotIterator<'_> {
+		SnapshotIterator {
+			snapshot: self,
+			index_iter: self.engine.indexer.iterator(iterator_options),
+			index_head: None,
+			history_cursor: IterCursor::new(iterator_options),
+		}
+	}
+
+	//写入时先保存旧版本再更新索引,所以这里要先读索引再读旧版本
+	fn get_pos(&self, key: &[u8]) -> Option<LogRecordPos> {
+		let pos = self.engine.indexer.get(key.to_vec());
+		match self.engine.history.get(key, self.seq_no) {
+			Some(old_pos) => old_pos,
+			None => pos,
+		}
+	}
+}
+
+impl Drop for Snapshot<'_> {
+	fn drop(&mut self) {
+		self.engine.history.release(self.seq_no);
+		//快照都释放之后,快照期间被覆盖的操作数链表不会再被读到
+		if !self.engine.history.has_snapshots() {
+			self.engine.operand_links.unlink_retired();
+		}
+	}
+}
+
+//快照的迭代器,按顺序合并内存索引中的key和快照之后被修改过的key,再还原出每个key在快照时的数据
+pub struct SnapshotIterator<'a> {
+	snapshot: &'a Snapshot<'a>,
+	index_iter: Box<dyn IndexIterator>,
+	//内存索引中预读的下一个key
+	index_head: Option<Vec<u8>>,
+	//旧版本的key每次都重新查找,迭代过程中新保存的旧版本也能被找到
+	history_cursor: IterCursor<()>,
+}
+
+impl SnapshotIterator<'_> {
+	pub fn rewind(&mut self) {
+		self.index_iter.rewind();
+		self.history_cursor.rewind();
+		self.index_head = None;
+	}
+	pub fn seek(&mut self, key: Vec<u8>) {
+		self.index_iter.seek(key.clone());
+		self.history_cursor.seek(key);
+		self.index_head = None;
+	}
+}
+
+impl std::iter::Iterator for SnapshotIterator<'_> {
+	type Item = Result<(Bytes, Bytes)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			//先读索引再读旧版本,快照之后才从索引中删除的key一定能在旧版本中找到
+			if self.index_head.is_none() {
+				self.index_head = self.index_iter.next().map(|(key, _)| key.clone());
+			}
+			let history = &self.snapshot.engine.history;
+			let history_head = self
+				.history_cursor
+				.next(|bound, ascending| history.lookup(bound, ascending))
+				.map(|(key, _)| key.clone());
+			let key = match (self.index_head.take(), history_head) {
+				(None, None) => return None,
+				(Some(key), None) | (None, Some(key)) => key,
+				(Some(index_key), Some(history_key)) => {
+					let order = index_key.cmp(&history_key);
+					let index_first = match self.history_cursor.is_reverse() {
+						false => order == Ordering::Less,
+						true => order == Ordering::Greater,
+					};
+					if index_first {
+						//旧版本的游标会回到index_key之后,下次还能找到history_key
+						index_key
+					} else {
+						if order != Ordering::Equal {
+							self.index_head = Some(index_key);
+						}
+						history_key
+					}
+				}
+			};
+			self.history_cursor.skip_past(key.clone());
+			//快照之后才写入的key在快照中不存在
+			if let Some(pos) = self.snapshot.get_pos(&key) {
+				match self.snapshot.engine.get_value_at(pos, self.snapshot.now) {
+					//已经过期的key
+					Err(Errors::KeyNotFound) => continue,
+					value => return Some(value.map(|value| (Bytes::from(key), value))),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use std::time::Duration;
+
+	use crate::options::{IndexType, Options, WriteBatchOptions};
+
+	use super::*;
+
+	fn open_engine(dir: &str, index_type: IndexType) -> Engine {
+		let opts = Options {
+			dir_path: PathBuf::from(dir),
+			data_file_size: 64 * 1024 * 1024,
+			sync_writes: false,
+			index_type,
+			..Default::default()
+		};
+		Engine::open(opts).expect("failed to open engine")
+	}
+
+	#[test]
+	fn test_snapshot_get() {
+		let engine = open_engine("/tmp/bitcask-rs-snapshot-get", IndexType::BTree);
+		engine.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+		engine.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+
+		let snapshot = engine.snapshot();
+		engine.put(Bytes::from("a"), Bytes::from("a2")).unwrap();
+		engine.put(Bytes::from("a"), Bytes::from("a3")).unwrap();
+		engine.delete(Bytes::from("b")).unwrap();
+		engine.put(Bytes::from("c"), Bytes::from("c1")).unwrap();
+
+		//快照中是修改之前的数据
+		assert_eq!(Bytes::from("a1"), snapshot.get(Bytes::from("a")).unwrap());
+		assert_eq!(Bytes::from("b1"), snapshot.get(Bytes::from("b")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), snapshot.get(Bytes::from("c")));
+		assert_eq!(Bytes::from("a3"), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("b")));
+
+		//第二个快照能看到第一个快照之后的写入
+		let snapshot2 = engine.snapshot();
+		engine.put(Bytes::from("c"), Bytes::from("c2")).unwrap();
+		assert_eq!(Bytes::from("a3"), snapshot2.get(Bytes::from("a")).unwrap());
+		assert_eq!(Bytes::from("c1"), snapshot2.get(Bytes::from("c")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), snapshot.get(Bytes::from("c")));
+
+		//快照释放后,不再需要的旧版本会被清理
+		drop(snapshot);
+		assert_eq!(1, engine.history.versions.read().len());
+		drop(snapshot2);
+		assert!(engine.history.versions.read().is_empty());
+		engine.put(Bytes::from("a"), Bytes::from("a4")).unwrap();
+		assert!(engine.history.versions.read().is_empty());
+
+		std::fs::remove_dir_all("/tmp/bitcask-rs-snapshot-get").expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_snapshot_with_write_batch() {
+		let engine = open_engine("/tmp/bitcask-rs-snapshot-batch", IndexType::SkipList);
+		engine.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+		let snapshot = engine.snapshot();
+
+		let wb = engine.new_write_batch(WriteBatchOptions::default());
+		wb.put(Bytes::from("a"), Bytes::from("a2")).unwrap();
+		wb.put(Bytes::from("b"), Bytes::from("b2")).unwrap();
+		wb.commit().unwrap();
+
+		//批量写入要么全部可见,要么全部不可见
+		assert_eq!(Bytes::from("a1"), snapshot.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), snapshot.get(Bytes::from("b")));
+		let snapshot2 = engine.snapshot();
+		assert!(snapshot2.seq_no() > snapshot.seq_no());
+		assert_eq!(Bytes::from("a2"), snapshot2.get(Bytes::from("a")).unwrap());
+		assert_eq!(Bytes::from("b2"), snapshot2.get(Bytes::from("b")).unwrap());
+
+		std::fs::remove_dir_all("/tmp/bitcask-rs-snapshot-batch").expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_snapshot_ttl() {
+		let engine = open_engine("/tmp/bitcask-rs-snapshot-ttl", IndexType::BTree);
+		engine.put_with_ttl(Bytes::from("a"), Bytes::from("a1"), Duration::from_millis(100)).unwrap();
+		engine.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+		let snapshot = engine.snapshot();
+		std::thread::sleep(Duration::from_millis(200));
+
+		//快照按创建时的时间判断是否过期
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("a")));
+		assert_eq!(Bytes::from("a1"), snapshot.get(Bytes::from("a")).unwrap());
+		let keys: Vec<Bytes> = snapshot
+			.iter(&IteratorOptions::default())
+			.map(|item| item.expect("failed to read value").0)
+			.collect();
+		assert_eq!(vec![Bytes::from("a"), Bytes::from("b")], keys);
+		let snapshot2 = engine.snapshot();
+		assert_eq!(Err(Errors::KeyNotFound), snapshot2.get(Bytes::from("a")));
+		assert_eq!(1, snapshot2.iter(&IteratorOptions::default()).count());
+
+		std::fs::remove_dir_all("/tmp/bitcask-rs-snapshot-ttl").expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_snapshot_iterator() {
+		let engine = open_engine("/tmp/bitcask-rs-snapshot-iter", IndexType::BTree);
+		for key in ["a", "b", "c", "d"] {
+			engine.put(Bytes::from(key), Bytes::from(key.repeat(2))).unwrap();
+		}
+		let snapshot = engine.snapshot();
+		engine.delete(Bytes::from("b")).unwrap();
+		engine.put(Bytes::from("bb"), Bytes::from("new")).unwrap();
+		engine.put(Bytes::from("c"), Bytes::from("new")).unwrap();
+		engine.delete(Bytes::from("d")).unwrap();
+		engine.put(Bytes::from("e"), Bytes::from("new")).unwrap();
+
+		let items = |iter: SnapshotIterator| -> Vec<(Bytes, Bytes)> {
+			iter.map(|item| item.expect("failed to read value")).collect()
+		};
+		let expected: Vec<(Bytes, Bytes)> = ["a", "b", "c", "d"]
+			.iter()
+			.map(|key| (Bytes::from(*key), Bytes::from(key.repeat(2))))
+			.collect();
+		assert_eq!(expected, items(snapshot.iter(&IteratorOptions::default())));
+
+		let reverse_opts = IteratorOptions {
+			reverse: true,
+			..Default::default()
+		};
+		let reversed: Vec<(Bytes, Bytes)> = expected.iter().rev().cloned().collect();
+		assert_eq!(reversed, items(snapshot.iter(&reverse_opts)));
+		let mut iter = snapshot.iter(&reverse_opts);
+		iter.seek("c".as_bytes().to_vec());
+		assert_eq!(Bytes::from("c"), iter.next().unwrap().unwrap().0);
+		assert_eq!(Bytes::from("b"), iter.next().unwrap().unwrap().0);
+		iter.rewind();
+		assert_eq!(Bytes::from("d"), iter.next().unwrap().unwrap().0);
+
+		let iter = snapshot.iter(&IteratorOptions {
+			prefix: "b".as_bytes().to_vec(),
+			..Default::default()
+		});
+		assert_eq!(vec![(Bytes::from("b"), Bytes::from("bb"))], items(iter));
+
+		//迭代过程中的写入不影响快照的迭代结果
+		let mut iter = snapshot.iter(&IteratorOptions::default());
+		assert_eq!(Bytes::from("a"), iter.next().unwrap().unwrap().0);
+		engine.delete(Bytes::from("c")).unwrap();
+		engine.delete(Bytes::from("a")).unwrap();
+		engine.put(Bytes::from("ba"), Bytes::from("new")).unwrap();
+		assert_eq!(expected[1..].to_vec(), items(iter));
+
+		drop(snapshot);
+		std::fs::remove_dir_all("/tmp/bitcask-rs-snapshot-iter").expect("failed to remove path");
+	}
+}
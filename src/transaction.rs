@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::batch::WriteBatch;
+use crate::db::Engine;
+use crate::errors::{Errors, Result};
+use crate::options::WriteBatchOptions;
+use crate::snapshot::Snapshot;
+
+//乐观事务,读取的是事务开始时的快照,写入先暂存在WriteBatch里面
+//提交时如果读过的key在事务开始之后被其他写入修改过,则提交失败
+pub struct Transaction<'a> {
+	snapshot: Snapshot<'a>,
+	write_batch: WriteBatch<'a>,
+	//事务读过的key
+	read_set: Mutex<HashSet<Vec<u8>>>,
+	engine: &'a Engine,
+}
+
+impl Engine {
+	//开启一个事务
+	pub fn begin_transaction(&self, write_batch_options: WriteBatchOptions) -> Transaction<'_> {
+		Transaction {
+			snapshot: self.snapshot(),
+			write_batch: self.new_write_batch(write_batch_options),
+			read_set: Mutex::new(HashSet::new()),
+			engine: self,
+		}
+	}
+}
+
+impl Transaction<'_> {
+	//先读事务自己写入的数据,再读事务开始时的快照
+	pub fn get(&self, key: Bytes) -> Result<Bytes> {
+		if key.is_empty() {
+			return Err(Errors::KeyIsEmpty);
+		}
+		if let Some(value) = self.write_batch.get_pending(&key)? {
+			return value.ok_or(Errors::KeyNotFound);
+		}
+		//不存在的key也要记录,其他写入创建了这个key也算冲突
+		self.read_set.lock().insert(key.to_vec());
+		self.snapshot.get(key)
+	}
+
+	pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+		self.write_batch.put(key, value)
+	}
+
+	pub fn delete(&self, key: Bytes) -> Result<()> {
+		self.write_batch.delete_always(key)
+	}
+
+	//冲突检测和写入在同一把锁里面完成,检测通过之后不会有其他写入插进来
+	pub fn commit(self) -> Result<()> {
+		let _lock = self.engine.batch_commit_lock.write();
+		let seq_no = self.snapshot.seq_no();
+		for key in self.read_set.lock().iter() {
+			if self.engine.history.modified_after(key, seq_no) {
+				return Err(Errors::TransactionConflict);
+			}
+		}
+		self.write_batch.commit_with_lock()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use crate::options::Options;
+
+	use super::*;
+
+	fn open_engine(dir: &str) -> Engine {
+		let opts = Options {
+			dir_path: PathBuf::from(dir),
+			data_file_size: 64 * 1024 * 1024,
+			sync_writes: false,
+			..Default::default()
+		};
+		Engine::open(opts).expect("failed to open engine")
+	}
+
+	#[test]
+	fn test_transaction_read_your_writes() {
+		let engine = open_engine("/tmp/bitcask-rs-txn-1");
+		engine.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+		engine.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		assert_eq!(Bytes::from("a1"), txn.get(Bytes::from("a")).unwrap());
+		txn.put(Bytes::from("a"), Bytes::from("a2")).unwrap();
+		txn.delete(Bytes::from("b")).unwrap();
+		assert_eq!(Bytes::from("a2"), txn.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), txn.get(Bytes::from("b")));
+		assert_eq!(Err(Errors::KeyIsEmpty), txn.get(Bytes::new()));
+		//提交之前其他人看不到事务的写入
+		assert_eq!(Bytes::from("a1"), engine.get(Bytes::from("a")).unwrap());
+		assert!(txn.commit().is_ok());
+		assert_eq!(Bytes::from("a2"), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("b")));
+
+		std::fs::remove_dir_all("/tmp/bitcask-rs-txn-1").expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_transaction_conflict() {
+		let engine = open_engine("/tmp/bitcask-rs-txn-2");
+		engine.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+
+		//读过的key被修改了
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		assert!(txn.get(Bytes::from("a")).is_ok());
+		txn.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+		engine.put(Bytes::from("a"), Bytes::from("a2")).unwrap();
+		assert_eq!(Err(Errors::TransactionConflict), txn.commit());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("b")));
+
+		//读过的不存在的key被创建了
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		assert_eq!(Err(Errors::KeyNotFound), txn.get(Bytes::from("c")));
+		txn.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+		engine.put(Bytes::from("c"), Bytes::from("c1")).unwrap();
+		assert_eq!(Err(Errors::TransactionConflict), txn.commit());
+
+		//被修改的key事务没有读过,不算冲突
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		assert!(txn.get(Bytes::from("a")).is_ok());
+		txn.put(Bytes::from("a"), Bytes::from("a3")).unwrap();
+		engine.put(Bytes::from("c"), Bytes::from("c2")).unwrap();
+		assert!(txn.commit().is_ok());
+
+		//两个事务读写同一个key,后提交的失败
+		let txn1 = engine.begin_transaction(WriteBatchOptions::default());
+		let txn2 = engine.begin_transaction(WriteBatchOptions::default());
+		assert!(txn1.get(Bytes::from("a")).is_ok());
+		assert!(txn2.get(Bytes::from("a")).is_ok());
+		txn1.put(Bytes::from("a"), Bytes::from("a4")).unwrap();
+		txn2.put(Bytes::from("a"), Bytes::from("a5")).unwrap();
+		assert!(txn1.commit().is_ok());
+		assert_eq!(Err(Errors::TransactionConflict), txn2.commit());
+		assert_eq!(Bytes::from("a4"), engine.get(Bytes::from("a")).unwrap());
+
+		std::fs::remove_dir_all("/tmp/bitcask-rs-txn-2").expect("failed to remove path");
+	}
+
+	#[test]
+	fn test_transaction_delete_absent_key() {
+		let engine = open_engine("/tmp/bitcask-rs-txn-3");
+
+		//删除时key还不存在,提交之前被其他写入创建了,删除不能丢失
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		txn.delete(Bytes::from("a")).unwrap();
+		assert_eq!(Err(Errors::KeyNotFound), txn.get(Bytes::from("a")));
+		engine.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+		assert!(txn.commit().is_ok());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("a")));
+
+		//删除之后再写入,以最后一次写入为准
+		let txn = engine.begin_transaction(WriteBatchOptions::default());
+		txn.delete(Bytes::from("b")).unwrap();
+		txn.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+		assert!(txn.commit().is_ok());
+		assert_eq!(Bytes::from("b1"), engine.get(Bytes::from("b")).unwrap());
+
+		std::fs::remove_dir_all("/tmp/bitcask-rs-txn-3").expect("failed to remove path");
+	}
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+
+use crate::data::log_record::LogRecordPos;
+use crate::db::Engine;
+use crate::errors::{Errors, Result};
+
+//当前的unix时间戳(毫秒)
+pub(crate) fn now_millis() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+//内存索引中设置了过期时间的位置,迭代时不用读取数据文件就能跳过过期的key
+//只保存索引当前指向的位置,被覆盖的位置在更新索引时删除
+#[derive(Default)]
+pub(crate) struct Expirations {
+	//(file_id, offset) -> 过期时间
+	expire_at: RwLock<HashMap<(u32, u64), u64>>,
+}
+
+impl Expirations {
+	pub(crate) fn set(&self, pos: LogRecordPos, expire_at: u64) {
+		if expire_at > 0 {
+			self.expire_at.write().insert((pos.file_id, pos.offset), expire_at);
+		}
+	}
+
+	pub(crate) fn remove(&self, pos: LogRecordPos) {
+		//没有设置过ttl时每次写入都会调用,只拿读锁检查,不和读取互相阻塞
+		if self.is_empty() {
+			return;
+		}
+		self.expire_at.write().remove(&(pos.file_id, pos.offset));
+	}
+
+	//pos处数据的过期时间,0表示永不过期
+	pub(crate) fn get(&self, pos: LogRecordPos) -> u64 {
+		self.expire_at.read().get(&(pos.file_id, pos.offset)).copied().unwrap_or(0)
+	}
+
+	pub(crate) fn is_expired(&self, pos: LogRecordPos, now: u64) -> bool {
+		self.expire_at.read().get(&(pos.file_id, pos.offset)).is_some_and(|expire_at| *expire_at <= now)
+	}
+
+	pub(crate) fn is_empty(&self) -> bool {
+		self.expire_at.read().is_empty()
+	}
+
+	//已经过期但还在内存索引中的位置的数量
+	pub(crate) fn count_expired(&self, now: u64) -> usize {
+		self.expire_at.read().values().filter(|expire_at| **expire_at <= now).count()
+	}
+}
+
+impl Engine {
+	//写入一条ttl之后过期的数据,过期之后读取返回KeyNotFound,merge时会被清理掉
+	pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+		if key.is_empty() {
+			return Err(Errors::KeyIsEmpty);
+		}
+		let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+		let _lock = self.batch_commit_lock.read();
+		self.put_with_lock(&key, &value, expire_at)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use crate::options::{IteratorOptions, Options};
+
+	use super::*;
+
+	#[test]
+	fn test_put_with_ttl() {
+		let opts = Options {
+			dir_path: PathBuf::from("/tmp/bitcask-rs-ttl"),
+			data_file_size: 64 * 1024 * 1024,
+			..Default::default()
+		};
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		let short = Duration::from_millis(100);
+		engine.put_with_ttl(Bytes::from("a"), Bytes::from("a1"), short).unwrap();
+		engine.put_with_ttl(Bytes::from("b"), Bytes::from("b1"), Duration::from_secs(3600)).unwrap();
+		engine.put(Bytes::from("c"), Bytes::from("c1")).unwrap();
+		engine.put_with_ttl(Bytes::from("d"), Bytes::from("d1"), short).unwrap();
+		//覆盖之后不再过期
+		engine.put(Bytes::from("d"), Bytes::from("d2")).unwrap();
+		assert_eq!(Bytes::from("a1"), engine.get(Bytes::from("a")).unwrap());
+		assert_eq!(Err(Errors::KeyIsEmpty), engine.put_with_ttl(Bytes::new(), Bytes::from("v"), short));
+
+		std::thread::sleep(short * 2);
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("a")));
+		assert_eq!(Bytes::from("b1"), engine.get(Bytes::from("b")).unwrap());
+		assert_eq!(Bytes::from("d2"), engine.get(Bytes::from("d")).unwrap());
+		let keys = vec![Bytes::from("b"), Bytes::from("c"), Bytes::from("d")];
+		assert_eq!(keys, engine.list_keys());
+		let iter_keys: Vec<Bytes> = engine
+			.iter(&IteratorOptions::default())
+			.map(|item| item.expect("failed to read value").0)
+			.collect();
+		assert_eq!(keys, iter_keys);
+		let rev_keys: Vec<Bytes> = engine.key_iter(&IteratorOptions::default()).rev().map(|(key, _)| key).collect();
+		assert_eq!(keys.iter().rev().cloned().collect::<Vec<_>>(), rev_keys);
+
+		//重启之后过期的key不会被加载到索引中
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(keys, engine.list_keys());
+		assert_eq!(3, engine.indexer.len());
+		engine.put_with_ttl(Bytes::from("e"), Bytes::from("e1"), short).unwrap();
+		engine.put_with_ttl(Bytes::from("f"), Bytes::from("f1"), Duration::from_secs(3600)).unwrap();
+
+		//merge之后未过期的key仍然带有过期时间
+		std::thread::sleep(short * 2);
+		engine.merge().expect("failed to merge");
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(4, engine.indexer.len());
+		assert_eq!(Err(Errors::KeyNotFound), engine.get(Bytes::from("e")));
+		assert_eq!(Bytes::from("f1"), engine.get(Bytes::from("f")).unwrap());
+		let pos = engine.indexer.get(b"f".to_vec()).unwrap();
+		assert!(!engine.expirations.is_expired(pos, now_millis()));
+		assert!(engine.expirations.is_expired(pos, now_millis() + 3600 * 1000));
+
+		//过期的key不计入key_num,merge之后才过期的数据在加载hint文件时计入可回收的空间
+		//过期时间要足够长,保证merge的时候还没有过期
+		let long = Duration::from_secs(1);
+		engine.put_with_ttl(Bytes::from("g"), Bytes::from("g1"), long).unwrap();
+		assert_eq!(5, engine.stat().unwrap().key_num);
+		engine.merge().expect("failed to merge");
+		std::thread::sleep(long);
+		assert_eq!(4, engine.stat().unwrap().key_num);
+		engine.close().expect("failed to close");
+		std::mem::drop(engine);
+		let engine = Engine::open(opts.clone()).expect("failed to open engine");
+		assert_eq!(4, engine.indexer.len());
+		let stat = engine.stat().unwrap();
+		assert_eq!(4, stat.key_num);
+		assert!(stat.reclaimable_size > 0);
+
+		std::fs::remove_dir_all(opts.dir_path).expect("failed to remove path");
+	}
+}
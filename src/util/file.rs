@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::Path;
+
+//目录下所有文件(包括子目录)所占磁盘空间的大小
+pub fn dir_disk_size(dir_path: &Path) -> u64 {
+	let entries = match fs::read_dir(dir_path) {
+		Ok(entries) => entries,
+		Err(_) => return 0,
+	};
+	let mut size = 0;
+	for entry in entries.flatten() {
+		let metadata = match entry.metadata() {
+			Ok(metadata) => metadata,
+			Err(_) => continue,
+		};
+		if metadata.is_dir() {
+			size += dir_disk_size(&entry.path());
+		} else {
+			size += metadata.len();
+		}
+	}
+	size
+}
+
+#[test]
+fn test_dir_disk_size() {
+	let dir_path = std::path::PathBuf::from("/tmp/bitcask-rs-dir-size");
+	fs::create_dir_all(dir_path.join("sub")).unwrap();
+	fs::write(dir_path.join("a.data"), "aaaa").unwrap();
+	fs::write(dir_path.join("sub").join("b.data"), "bbbbbb").unwrap();
+	assert_eq!(10, dir_disk_size(&dir_path));
+	assert_eq!(0, dir_disk_size(&dir_path.join("not-exist")));
+	fs::remove_dir_all(dir_path).unwrap();
+}